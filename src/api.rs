@@ -1,12 +1,29 @@
-use log::debug;
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::StreamExt;
+use log::{debug, warn};
 use post_archiver_utils::{ArchiveClient, Error, Result};
 use reqwest::{
-    Client,
-    header::{self, HeaderMap},
+    Client, Method, Response, StatusCode,
+    header::{self, HeaderMap, HeaderValue},
 };
 use serde::{Deserialize, de::DeserializeOwned};
+use tempfile::{NamedTempFile, TempPath};
 
-use crate::config::Config;
+use crate::{
+    config::Config,
+    dns::DohResolver,
+    error::{PixivError, PixivResult},
+    proxy::ProxyPool,
+    throttle::Throttle,
+};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct PixivResponse<T> {
@@ -51,6 +68,50 @@ impl<T> PixivResponseUnwrap<T> {
 #[derive(Debug, Clone)]
 pub struct PixivClient {
     inner: ArchiveClient,
+    /// client of the image hosts, limited apart from the api
+    images: ArchiveClient,
+    resume: bool,
+    retry: RetryPolicy,
+    throttle: Throttle,
+    block_cooldown: Duration,
+    sessions: Arc<SessionPool>,
+    proxies: Arc<ProxyPool>,
+    pximg_mirror: Option<String>,
+    origin: Option<reqwest::Url>,
+}
+
+/// Sessions taking turns on the requests, `every` requests each
+#[derive(Debug)]
+struct SessionPool {
+    cookies: Vec<HeaderValue>,
+    every: usize,
+    requests: AtomicUsize,
+}
+
+impl SessionPool {
+    fn next(&self) -> HeaderValue {
+        let request = self.requests.fetch_add(1, Ordering::Relaxed);
+        self.cookies[request / self.every % self.cookies.len()].clone()
+    }
+}
+
+/// How often and how long to wait before retrying a transient error
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Exponential backoff of the attempt, jittered between 50% and 150%
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.delay * 2u32.saturating_pow(attempt.saturating_sub(1));
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        delay.mul_f64(0.5 + (nanos % 1000) as f64 / 1000.0)
+    }
 }
 
 impl PixivClient {
@@ -58,19 +119,59 @@ impl PixivClient {
         let mut default_headers = Self::generate_user_headers(&config.user_agent);
         debug!("Using headers: {default_headers:#?} (without cookies)");
 
-        default_headers.insert(header::COOKIE, format!("PHPSESSID={}", config.session).parse().unwrap());
+        let cookies = std::iter::once(&config.session)
+            .chain(&config.sessions)
+            .map(|session| format!("PHPSESSID={session}").parse().unwrap())
+            .collect::<Vec<HeaderValue>>();
+        default_headers.insert(header::COOKIE, cookies[0].clone());
 
-        let inner = ArchiveClient::builder(
-            Client::builder()
-                .default_headers(default_headers)
+        let build = |proxy: Option<&str>, limit: u32| {
+            let mut client = Client::builder().default_headers(default_headers.clone());
+            for pinned in &config.resolve {
+                client = client.resolve(&pinned.host, pinned.addr);
+            }
+            if let Some(endpoint) = &config.doh {
+                client = client.dns_resolver(Arc::new(DohResolver::new(endpoint)));
+            }
+            if let Some(proxy) = proxy {
+                client = client.proxy(reqwest::Proxy::all(proxy).unwrap());
+            }
+            // `PixivClient::retry` is the only retry loop, it knows about the throttle
+            ArchiveClient::builder(client.build().unwrap(), limit)
+                .pre_sec_limit((limit as f32 / 60.0).ceil() as u32)
+                .retry_limit(0)
                 .build()
-                .unwrap(),
-            config.limit,
-        )
-        .pre_sec_limit((config.limit as f32 / 60.0).ceil() as u32)
-        .build();
+        };
+        let inner = build(None, config.limit);
+        let images = build(None, config.image_limit);
+        let proxies = config
+            .proxies
+            .iter()
+            .map(|proxy| {
+                let client = build(Some(proxy), config.limit);
+                (proxy.clone(), client, build(Some(proxy), config.image_limit))
+            })
+            .collect();
 
-        Self { inner }
+        Self {
+            inner,
+            images,
+            resume: config.resume_downloads,
+            retry: RetryPolicy {
+                attempts: config.retries,
+                delay: Duration::from_secs(config.retry_delay),
+            },
+            throttle: config.throttle.clone(),
+            block_cooldown: Duration::from_secs(config.block_cooldown),
+            sessions: Arc::new(SessionPool {
+                cookies,
+                every: config.rotate_every.max(1),
+                requests: AtomicUsize::new(0),
+            }),
+            proxies: Arc::new(ProxyPool::new(proxies)),
+            pximg_mirror: config.pximg_mirror.clone(),
+            origin: config.origin.clone(),
+        }
     }
 
     pub fn generate_user_headers(user_agent: &str) -> HeaderMap {
@@ -163,14 +264,460 @@ impl PixivClient {
         ])
     }
 
-    pub async fn fetch<T: DeserializeOwned>(&self, url: &str) -> Result<T> {
-        self.inner
-            .fetch::<PixivResponse<T>>(url)
-            .await
-            .and_then(|r| r.downcast())
+    /// Run the request again with backoff while it fails with a transient error.
+    /// Rate limits wait for the throttle instead, without using up the attempts.
+    async fn retry<T, F>(&self, url: &str, request: impl Fn() -> F) -> PixivResult<T>
+    where
+        F: Future<Output = PixivResult<T>>,
+    {
+        const MAX_THROTTLES: u32 = 10;
+
+        let mut attempt = 0;
+        let mut throttled = 0;
+        loop {
+            self.throttle.wait(!self.is_image(url)).await;
+            match request().await {
+                Err(e @ (PixivError::Rate | PixivError::Blocked)) if throttled < MAX_THROTTLES => {
+                    throttled += 1;
+                    debug!("Request to {url} failed ({e}), waiting for the throttle");
+                }
+                Err(e) if attempt < self.retry.attempts && e.is_transient() => {
+                    attempt += 1;
+                    let delay = self.retry.backoff(attempt);
+                    warn!(
+                        "Request to {url} failed ({e}), retry {attempt}/{} in {:.1}s",
+                        self.retry.attempts,
+                        delay.as_secs_f32()
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Send the request with the next session, through the next proxy of the pool
+    async fn send(&self, method: Method, url: &str, range: Option<u64>) -> PixivResult<Response> {
+        let image = self.is_image(url);
+        let proxy = self.proxies.pick();
+        let client = match proxy {
+            Some(proxy) => self.proxies.client(proxy, image),
+            None if image => &self.images,
+            None => &self.inner,
+        };
+        let mut request = client
+            .request(method, self.routed(url))
+            .header(header::COOKIE, self.sessions.next());
+        if let Some(start) = range {
+            request = request.header(header::RANGE, format!("bytes={start}-"));
+        }
+
+        let response = request.send().await;
+        let ok = response.as_ref().is_ok_and(|response| {
+            !matches!(
+                response.status(),
+                StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS
+            )
+        });
+        self.proxies.report(proxy, ok);
+
+        let response = response.map_err(Error::from)?;
+        self.throttle.check(&response);
+        Ok(response)
+    }
+
+    pub async fn fetch<T: DeserializeOwned>(&self, url: &str) -> PixivResult<T> {
+        self.retry(url, || self.fetch_once(url)).await
+    }
+
+    async fn fetch_once<T: DeserializeOwned>(&self, url: &str) -> PixivResult<T> {
+        self.fetch_with_raw_once(url).await.map(|(body, _)| body)
+    }
+
+    /// Like [`Self::fetch`], also returning the untouched json of the response
+    pub async fn fetch_with_raw<T: DeserializeOwned>(
+        &self,
+        url: &str,
+    ) -> PixivResult<(T, Vec<u8>)> {
+        self.retry(url, || self.fetch_with_raw_once(url)).await
+    }
+
+    async fn fetch_with_raw_once<T: DeserializeOwned>(
+        &self,
+        url: &str,
+    ) -> PixivResult<(T, Vec<u8>)> {
+        let response = self.send(Method::GET, url, None).await?;
+        let status = response.status();
+        let bytes = response.bytes().await.map_err(Error::from)?;
+
+        match serde_json::from_slice::<PixivResponse<T>>(&bytes) {
+            Ok(PixivResponse {
+                body: NullableBody::Some(body),
+                ..
+            }) => Ok((body, bytes.into())),
+            Ok(PixivResponse { message, .. }) => Err(PixivError::from_response(status, message)),
+            Err(_) if is_html(&bytes) => Err(self.blocked(url)),
+            Err(_) if !status.is_success() => {
+                Err(PixivError::from_response(status, String::new()))
+            }
+            Err(e) => Err(PixivError::Parse(e.to_string())),
+        }
+    }
+
+    /// Fetch json that isn't wrapped in the ajax `{ error, message, body }` envelope
+    pub async fn fetch_raw<T: DeserializeOwned>(&self, url: &str) -> PixivResult<T> {
+        self.retry(url, || self.fetch_raw_once(url)).await
+    }
+
+    async fn fetch_raw_once<T: DeserializeOwned>(&self, url: &str) -> PixivResult<T> {
+        let response = self.send(Method::GET, url, None).await?;
+        let status = response.status();
+        let bytes = response.bytes().await.map_err(Error::from)?;
+        if is_html(&bytes) {
+            return Err(self.blocked(url));
+        }
+        if !status.is_success() {
+            return Err(PixivError::from_response(status, String::new()));
+        }
+
+        serde_json::from_slice(&bytes).map_err(|e| PixivError::Parse(e.to_string()))
+    }
+
+    /// Pause all requests for the cool-down, after Pixiv answered with a block or captcha page
+    fn blocked(&self, url: &str) -> PixivError {
+        warn!(
+            "Pixiv answered {url} with an html page, the client is likely blocked or asked for a captcha"
+        );
+        self.throttle.pause(self.block_cooldown, "blocked");
+        PixivError::Blocked
+    }
+
+    /// Final url after following the redirects of `url`
+    pub async fn resolve_redirect(&self, url: &str) -> PixivResult<reqwest::Url> {
+        let response = self.send(Method::GET, url, None).await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(PixivError::from_response(status, String::new()));
+        }
+        Ok(response.url().clone())
+    }
+
+    /// Whether the url is on an image host, which has its own rate limit
+    fn is_image(&self, url: &str) -> bool {
+        let Ok(url) = reqwest::Url::parse(url) else {
+            return false;
+        };
+        url.host_str().is_some_and(|host| {
+            host.ends_with("pximg.net") || self.pximg_mirror.as_deref() == Some(host)
+        })
+    }
+
+    /// The url on the `--pximg-mirror` host, for images of `i.pximg.net`
+    fn mirrored(&self, url: &str) -> String {
+        match (&self.pximg_mirror, url.strip_prefix("https://i.pximg.net/")) {
+            (Some(mirror), Some(path)) => format!("https://{mirror}/{path}"),
+            _ => url.to_string(),
+        }
+    }
+
+    /// The url on the `--origin` server, for the hosts of pixiv
+    fn routed(&self, url: &str) -> String {
+        let (Some(origin), Ok(mut routed)) = (&self.origin, reqwest::Url::parse(url)) else {
+            return url.to_string();
+        };
+        let pixiv = routed
+            .host_str()
+            .is_some_and(|host| host.ends_with("pixiv.net") || host.ends_with("pximg.net"));
+        if !pixiv {
+            return url.to_string();
+        }
+
+        // only the path and query are kept
+        let _ = routed.set_scheme(origin.scheme());
+        let _ = routed.set_host(origin.host_str());
+        let _ = routed.set_port(origin.port());
+        routed.to_string()
+    }
+
+    /// Size of the file by a `HEAD` request, if the server reports it
+    pub async fn content_length(&self, url: &str) -> PixivResult<Option<u64>> {
+        let url = &self.mirrored(url);
+        let response = self.send(Method::HEAD, url, None).await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(PixivError::from_response(status, String::new()));
+        }
+
+        Ok(response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse().ok()))
+    }
+
+    /// Download into a temp file, failing on non-success status.
+    /// With `resume_downloads`, broken transfers continue by a `Range` request when supported.
+    pub async fn download(&self, url: &str) -> PixivResult<TempPath> {
+        // kept across the retries, so they continue from where the last attempt broke
+        let partial = tokio::sync::Mutex::new(PartialDownload {
+            file: NamedTempFile::new().map_err(Error::from)?,
+            written: 0,
+            expected: None,
+            resumable: false,
+        });
+        self.retry(url, || async {
+            self.download_once(url, &mut *partial.lock().await).await
+        })
+        .await?;
+
+        let mut partial = partial.into_inner();
+        partial.file.flush().map_err(Error::from)?;
+        Ok(partial.file.into_temp_path())
+    }
+
+    async fn download_once(&self, url: &str, partial: &mut PartialDownload) -> PixivResult<()> {
+        const MAX_RESUMES: u32 = 5;
+        let url = &self.mirrored(url);
+
+        let mut resumes = 0;
+        loop {
+            let range = (partial.written > 0).then_some(partial.written);
+            if let Some(written) = range {
+                debug!("Continuing download of {url} from {written} bytes");
+            }
+
+            let response = self.send(Method::GET, url, range).await?;
+            let status = response.status();
+            if !status.is_success() {
+                return Err(PixivError::from_response(status, String::new()));
+            }
+
+            if partial.written == 0 {
+                partial.resumable = self.resume
+                    && response
+                        .headers()
+                        .get(header::ACCEPT_RANGES)
+                        .is_some_and(|ranges| ranges == "bytes");
+            } else if status != StatusCode::PARTIAL_CONTENT {
+                warn!("Server ignored range request for {url}, downloading from start");
+                partial.reset()?;
+            }
+            if partial.written == 0 {
+                partial.expected = response.content_length();
+            }
+
+            let mut stream = response.bytes_stream();
+            let mut failed = None;
+            while let Some(bytes) = stream.next().await {
+                let bytes = match bytes {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        failed = Some(e);
+                        break;
+                    }
+                };
+                partial.file.write_all(&bytes).map_err(Error::from)?;
+                partial.written += bytes.len() as u64;
+            }
+
+            let failed = match failed {
+                Some(e) => Some(e.to_string()),
+                None => match partial.expected {
+                    Some(expected) if partial.written < expected => Some(format!(
+                        "truncated at {} of {expected} bytes",
+                        partial.written
+                    )),
+                    _ => None,
+                },
+            };
+
+            match failed {
+                None => return partial.check(url),
+                Some(e) if partial.resumable && resumes < MAX_RESUMES => {
+                    resumes += 1;
+                    warn!(
+                        "Download of {url} broke at {} bytes ({e}), resuming",
+                        partial.written
+                    );
+                }
+                // a retry continues the partial file when it can, and starts over otherwise
+                Some(e) => {
+                    if !partial.resumable {
+                        partial.reset()?;
+                    }
+                    return Err(PixivError::Network(format!("download of {url} broke: {e}")));
+                }
+            }
+        }
     }
 
     pub fn as_inner(&self) -> &ArchiveClient {
         &self.inner
     }
 }
+
+/// Temp file of a download, with the bytes received so far
+struct PartialDownload {
+    file: NamedTempFile,
+    written: u64,
+    /// `Content-Length` of the whole file
+    expected: Option<u64>,
+    resumable: bool,
+}
+
+impl PartialDownload {
+    /// Check the finished file against its length and the markers of its format,
+    /// starting over on a mismatch so the retry downloads it again
+    fn check(&mut self, url: &str) -> PixivResult<()> {
+        let broken = match self.expected {
+            Some(expected) if self.written != expected => Some(format!(
+                "got {} bytes, expected {expected}",
+                self.written
+            )),
+            _ => self.sniff(url).err().map(str::to_string),
+        };
+
+        match broken {
+            None => Ok(()),
+            Some(reason) => {
+                warn!("Download of {url} is incomplete ({reason}), downloading again");
+                self.reset()?;
+                Err(PixivError::Network(format!("incomplete download of {url}: {reason}")))
+            }
+        }
+    }
+
+    /// Compare the head and tail of the file with the format the url extension names
+    fn sniff(&self, url: &str) -> std::result::Result<(), &'static str> {
+        const TAIL: usize = 64;
+
+        let path = url.split(['?', '#']).next().unwrap_or(url);
+        let ext = path.rsplit_once('.').map(|(_, ext)| ext.to_ascii_lowercase());
+        let Some(ext) = ext else {
+            return Ok(());
+        };
+
+        let mut file = self.file.reopen().map_err(|_| "failed to reopen")?;
+        let mut head = [0u8; 8];
+        let read = file.read(&mut head).map_err(|_| "failed to read")?;
+        let head = &head[..read];
+
+        let len = file.metadata().map_err(|_| "failed to read")?.len();
+        file.seek(SeekFrom::Start(len.saturating_sub(TAIL as u64)))
+            .map_err(|_| "failed to read")?;
+        let mut tail = vec![];
+        file.read_to_end(&mut tail).map_err(|_| "failed to read")?;
+        let has = |marker: &[u8]| tail.windows(marker.len()).any(|window| window == marker);
+
+        match ext.as_str() {
+            "jpg" | "jpeg" if !head.starts_with(&[0xFF, 0xD8, 0xFF]) => Err("not a jpeg"),
+            "jpg" | "jpeg" if !has(&[0xFF, 0xD9]) => Err("jpeg without end marker"),
+            "png" if !head.starts_with(b"\x89PNG") => Err("not a png"),
+            "png" if !has(b"IEND") => Err("png without end chunk"),
+            "gif" if !head.starts_with(b"GIF8") => Err("not a gif"),
+            "gif" if tail.last() != Some(&0x3B) => Err("gif without trailer"),
+            "zip" if !head.starts_with(b"PK") => Err("not a zip"),
+            "zip" if !has(b"PK\x05\x06") => Err("zip without central directory"),
+            _ => Ok(()),
+        }
+    }
+
+    fn reset(&mut self) -> PixivResult<()> {
+        self.file.as_file_mut().set_len(0).map_err(Error::from)?;
+        self.file
+            .as_file_mut()
+            .seek(SeekFrom::Start(0))
+            .map_err(Error::from)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+/// Whether the body is an html page, which Pixiv sends instead of json when blocking the client
+fn is_html(bytes: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(256)]).to_ascii_lowercase();
+    let head = head.trim_start();
+    head.starts_with("<!doctype html") || head.starts_with("<html")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_with_jitter() {
+        let policy = RetryPolicy {
+            attempts: 5,
+            delay: Duration::from_secs(2),
+        };
+        for (attempt, base) in [(0, 2), (1, 2), (2, 4), (3, 8), (4, 16)] {
+            let base = Duration::from_secs(base);
+            let delay = policy.backoff(attempt);
+            assert!(delay >= base / 2, "attempt {attempt}: {delay:?}");
+            assert!(delay <= base * 3 / 2, "attempt {attempt}: {delay:?}");
+        }
+    }
+
+    #[test]
+    fn backoff_saturates() {
+        let policy = RetryPolicy {
+            attempts: 1,
+            delay: Duration::from_millis(1),
+        };
+        // the factor saturates at u32::MAX instead of overflowing
+        assert!(policy.backoff(40) >= Duration::from_millis(1 << 30));
+    }
+
+    #[tokio::test]
+    async fn retry_transient_errors_only() {
+        const URL: &str = "https://www.pixiv.net/ajax/illust/1";
+        let mut config = Config::default();
+        config.retries = 3;
+        config.retry_delay = 0;
+        let client = PixivClient::new(&config);
+
+        // a server error is retried until it succeeds
+        let calls = &AtomicUsize::new(0);
+        let result = client
+            .retry(URL, move || async move {
+                match calls.fetch_add(1, Ordering::Relaxed) {
+                    0 | 1 => Err(PixivError::from_response(
+                        StatusCode::BAD_GATEWAY,
+                        String::new(),
+                    )),
+                    _ => Ok(()),
+                }
+            })
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+
+        // or until the attempts are used up
+        let calls = &AtomicUsize::new(0);
+        let result = client
+            .retry(URL, move || async move {
+                calls.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>(PixivError::from_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    String::new(),
+                ))
+            })
+            .await;
+        assert!(matches!(result, Err(PixivError::Http(_))));
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+
+        // a deleted work is returned at once
+        let calls = &AtomicUsize::new(0);
+        let result = client
+            .retry(URL, move || async move {
+                calls.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>(PixivError::from_response(
+                    StatusCode::BAD_REQUEST,
+                    "該当作品は削除されたか、存在しない作品IDです。".to_string(),
+                ))
+            })
+            .await;
+        assert!(matches!(result, Err(PixivError::Deleted(_))));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,46 +1,73 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
 
 use chrono::{DateTime, Utc};
 use futures::try_join;
 use log::{error, info, trace, warn};
 use plyne::{Input, Output};
 use post_archiver::{
-    Comment,
-    importer::{UnsyncCollection, UnsyncContent, UnsyncFileMeta, UnsyncPost},
+    AuthorId, Comment, PlatformId, PostId,
+    importer::{UnsyncCollection, UnsyncContent, UnsyncFileMeta, UnsyncPost, UnsyncTag},
 };
 use post_archiver_utils::Result;
+use regex::Regex;
 use reqwest::Url;
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{Value, json};
 use serde_repr::Deserialize_repr;
+use sha2::{Digest, Sha256};
 use tempfile::TempPath;
 use tokio::{
     fs::{File, OpenOptions, create_dir_all},
-    io, join,
+    io::{self, AsyncReadExt},
+    join,
+    sync::Semaphore,
     task::JoinSet,
+    time::{Instant, timeout_at},
 };
 
 use crate::{
     FileEvent, Manager, SyncEvent,
     api::PixivClient,
-    config::{Config, Progress},
-    file::{ArchiveRequest, PixivUgoira},
+    comment::{CommentImage, CommentOptions},
+    config::{
+        AvatarSize, CommentEmoji, Config, Layout, Progress, Quality, ResizeMode,
+        TranscodeFormat, UgoiraFormat,
+    },
+    disk,
+    error::PixivError,
+    file::{ArchiveRequest, PixivUgoira, gzip_sidecar, json_sidecar, sanitize},
+    remote::Remote,
+    series::{PixivSeriesId, fetch_series_detail, record_series_order, save_series_detail},
+    storage::{Metadata, Storage},
     tag::PixivTags,
-    user::UserManager,
+    url_map::UrlMap,
+    user::{UserManager, fetch_profile, save_profile},
 };
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
 #[serde(untagged)]
 pub enum PixivArtworkId {
     Illust(u64),
     Novel(u64),
+    /// Unlisted works are only reachable by their secret key
+    UnlistedIllust(String),
+    UnlistedNovel(String),
 }
 
 impl PixivArtworkId {
-    pub fn id(&self) -> u64 {
+    pub fn id(&self) -> Option<u64> {
         match self {
-            PixivArtworkId::Illust(id) => *id,
-            PixivArtworkId::Novel(id) => *id,
+            PixivArtworkId::Illust(id) => Some(*id),
+            PixivArtworkId::Novel(id) => Some(*id),
+            PixivArtworkId::UnlistedIllust(_) | PixivArtworkId::UnlistedNovel(_) => None,
         }
     }
 
@@ -48,6 +75,8 @@ impl PixivArtworkId {
         match self {
             PixivArtworkId::Illust(_) => "Illust",
             PixivArtworkId::Novel(_) => "Novel",
+            PixivArtworkId::UnlistedIllust(_) => "Unlisted Illust",
+            PixivArtworkId::UnlistedNovel(_) => "Unlisted Novel",
         }
     }
 
@@ -55,6 +84,12 @@ impl PixivArtworkId {
         match self {
             PixivArtworkId::Illust(id) => format!("https://www.pixiv.net/artworks/{id}"),
             PixivArtworkId::Novel(id) => format!("https://www.pixiv.net/novel/show.php?id={id}"),
+            PixivArtworkId::UnlistedIllust(key) => {
+                format!("https://www.pixiv.net/artworks/unlisted/{key}")
+            }
+            PixivArtworkId::UnlistedNovel(key) => {
+                format!("https://www.pixiv.net/novel/unlisted/{key}")
+            }
         }
     }
 
@@ -62,6 +97,12 @@ impl PixivArtworkId {
         match self {
             PixivArtworkId::Illust(id) => format!("https://www.pixiv.net/ajax/illust/{id}"),
             PixivArtworkId::Novel(id) => format!("https://www.pixiv.net/ajax/novel/{id}"),
+            PixivArtworkId::UnlistedIllust(key) => {
+                format!("https://www.pixiv.net/ajax/illust/unlisted/{key}")
+            }
+            PixivArtworkId::UnlistedNovel(key) => {
+                format!("https://www.pixiv.net/ajax/novel/unlisted/{key}")
+            }
         }
     }
 }
@@ -73,14 +114,26 @@ pub struct PixivArtwork {
     pub title: String,
     pub user_id: String,
     pub user_name: String,
+    #[serde(default)]
+    pub user_account: String,
     pub ai_type: AiType,
+    #[serde(default)]
+    pub x_restrict: ContentRestrict,
 
     pub comment_count: u32,
     pub comment_off: u8,
+    #[serde(default)]
+    pub bookmark_count: u64,
+    #[serde(default)]
+    pub like_count: u64,
+    #[serde(default)]
+    pub view_count: u64,
 
     pub create_date: String,
     pub upload_date: String,
     pub description: String,
+    #[serde(default)]
+    pub page_count: u32,
 
     // pub extra_data: PixivArtworkExtraData, // Ignored for now, this is how to display when share at other platforms
     #[serde(flatten)]
@@ -94,6 +147,14 @@ impl PixivArtwork {
     pub fn has_comment(&self) -> bool {
         self.comment_off == 0 && self.comment_count > 0
     }
+
+    pub fn series_id(&self) -> Option<PixivSeriesId> {
+        let id = self.series_nav_data.as_ref()?.series_id.parse().ok()?;
+        Some(match self.content {
+            PixivArtworkContent::Illust { .. } => PixivSeriesId::Illust(id),
+            PixivArtworkContent::Novel { .. } => PixivSeriesId::Novel(id),
+        })
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -108,6 +169,7 @@ pub enum PixivArtworkContent {
     Novel {
         content: String,
         cover_url: String,
+        text_embedded_images: Option<HashMap<String, PixivNovelImage>>,
     },
 }
 
@@ -128,8 +190,9 @@ pub enum IllustType {
 }
 
 #[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize_repr)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize_repr)]
 pub enum ContentRestrict {
+    #[default]
     General = 0,
     R18 = 1,
     R18G = 2,
@@ -140,6 +203,9 @@ pub enum ContentRestrict {
 pub struct PixivArtworkNavData {
     series_id: String,
     title: String,
+    /// position of the work in the series, starting from 1
+    #[serde(default)]
+    pub order: u32,
 }
 
 impl PixivArtworkNavData {
@@ -155,6 +221,16 @@ impl PixivArtworkNavData {
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct PixivNovelImage {
+    pub urls: PixivNovelImageUrls,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PixivNovelImageUrls {
+    pub original: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct PixivIllustPages {
     pub urls: PixivIllustPageUrls,
@@ -170,6 +246,157 @@ pub struct PixivIllustPageUrls {
     pub thumb_mini: String,
 }
 
+/// Options of the artworks pipeline, copied out of [`Config`] for the resolve tasks
+#[derive(Debug, Clone)]
+pub struct ArtworkOptions {
+    pub ugoira_poster: bool,
+    pub comments: bool,
+    pub max_comments: Option<usize>,
+    pub comment_stamps: bool,
+    pub comment_emoji: CommentEmoji,
+    pub comment_avatars: bool,
+    pub avatar_size: AvatarSize,
+    pub related: bool,
+    pub follow_related: u32,
+    pub max_original_bytes: Option<u64>,
+    pub quality: Quality,
+    pub max_pages: Option<usize>,
+    pub max_file_size: Option<u64>,
+    pub ugoira_format: UgoiraFormat,
+    pub ugoira_sidecars: bool,
+    pub raw_json: bool,
+    pub gallery_dl_metadata: bool,
+    /// `--layout plain`, which keeps the metadata in sidecars instead of the database
+    pub plain: bool,
+    pub update: bool,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub include_tags: Vec<String>,
+    pub exclude_tags: Vec<String>,
+    pub exclude_users: Vec<u64>,
+    pub blocked_tags: Vec<Regex>,
+    pub only_r18: bool,
+    pub no_r18: bool,
+    pub no_r18g: bool,
+    pub no_ai: bool,
+    pub only_ai: bool,
+    pub filename_template: Option<String>,
+    /// format and quality of `--transcode`, only when ffmpeg is available
+    pub transcode: Option<(TranscodeFormat, u8)>,
+    pub thumbnails: Option<u32>,
+}
+
+impl From<&Config> for ArtworkOptions {
+    fn from(config: &Config) -> Self {
+        Self {
+            ugoira_poster: !config.no_ugoira_poster,
+            comments: !config.no_comments,
+            max_comments: config.max_comments,
+            comment_stamps: config.comment_stamps,
+            comment_emoji: config.comment_emoji,
+            comment_avatars: config.comment_avatars,
+            avatar_size: config.avatar_size,
+            related: config.related,
+            follow_related: config.follow_related,
+            max_original_bytes: config.max_original_bytes,
+            quality: config.quality,
+            max_pages: config.max_pages,
+            max_file_size: config.max_file_size,
+            // the internal encoder only supports gif
+            ugoira_format: match config.has_ffmpeg {
+                true => config.ugoira_format,
+                false => UgoiraFormat::Gif,
+            },
+            ugoira_sidecars: config.ugoira_sidecars,
+            raw_json: config.raw_json,
+            gallery_dl_metadata: config.gallery_dl_metadata,
+            plain: config.layout == Layout::Plain,
+            update: config.update,
+            since: config.since,
+            until: config.until,
+            include_tags: config.include_tags.clone(),
+            exclude_tags: config.exclude_tags.clone(),
+            exclude_users: config.exclude_users.clone(),
+            blocked_tags: config.blocked_tags.clone(),
+            only_r18: config.only_r18,
+            no_r18: config.no_r18,
+            no_r18g: config.no_r18g,
+            no_ai: config.no_ai,
+            only_ai: config.only_ai,
+            filename_template: config.filename_template.clone(),
+            transcode: config
+                .transcode
+                .filter(|_| config.has_ffmpeg)
+                .map(|format| (format, config.transcode_quality)),
+            thumbnails: config.thumbnails,
+        }
+    }
+}
+
+impl ArtworkOptions {
+    /// Filename of the page by `--filename-template`, keeping `name` from the url without one
+    pub fn page_filename(&self, artwork: &PixivArtwork, page: usize, name: &str) -> String {
+        let Some(template) = &self.filename_template else {
+            return name.to_string();
+        };
+
+        let (stem, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+        template
+            .replace("{id}", &artwork.id)
+            .replace("{page}", &page.to_string())
+            .replace("{title}", &sanitize(&artwork.title))
+            .replace("{user_id}", &artwork.user_id)
+            .replace("{user}", &sanitize(&artwork.user_name))
+            .replace("{name}", stem)
+            .replace("{ext}", ext)
+    }
+
+    /// Reason to skip the artwork, if it doesn't pass the filters
+    pub fn filter(&self, artwork: &PixivArtwork) -> Option<String> {
+        if let Ok(user) = artwork.user_id.parse()
+            && self.exclude_users.contains(&user)
+        {
+            return Some(format!("by excluded user {user}"));
+        }
+
+        let created = common::parse_date(&artwork.create_date);
+        if self.since.is_some_and(|since| created < since) {
+            return Some(format!("created before --since ({created})"));
+        }
+        if self.until.is_some_and(|until| created > until) {
+            return Some(format!("created after --until ({created})"));
+        }
+
+        match artwork.x_restrict {
+            ContentRestrict::General if self.only_r18 => return Some("not R-18".to_string()),
+            ContentRestrict::R18 if self.no_r18 => return Some("R-18".to_string()),
+            ContentRestrict::R18G if self.no_r18g => return Some("R-18G".to_string()),
+            _ => {}
+        }
+
+        let is_ai = artwork.ai_type == AiType::Yes;
+        if self.no_ai && is_ai {
+            return Some("AI-generated".to_string());
+        }
+        if self.only_ai && !is_ai {
+            return Some("not AI-generated".to_string());
+        }
+
+        let has_tag = |tag: &String| artwork.tags.tags.iter().any(|t| &t.tag == tag);
+        if !self.include_tags.is_empty() && !self.include_tags.iter().any(has_tag) {
+            return Some("no tag of --include-tags".to_string());
+        }
+        if let Some(tag) = self.exclude_tags.iter().find(|tag| has_tag(tag)) {
+            return Some(format!("tagged with excluded {tag:?}"));
+        }
+        let blocked = |tag: &str| self.blocked_tags.iter().any(|pattern| pattern.is_match(tag));
+        if let Some(tag) = artwork.tags.tags.iter().find(|t| blocked(&t.tag)) {
+            return Some(format!("tagged with blocked {:?}", tag.tag));
+        }
+        None
+    }
+}
+
 pub async fn resolve_artworks(
     mut artworks_pipeline: Output<PixivArtworkId>,
     files_pipeline: Input<FileEvent>,
@@ -178,191 +405,706 @@ pub async fn resolve_artworks(
     manager: &Manager,
     config: &Config,
 ) {
-    let pb = Progress::new(config.multi.clone(), "artwork");
+    let pb = Progress::new(config, "artwork");
+    let options = Arc::new(ArtworkOptions::from(config));
+    let related_semaphore = Arc::new(Semaphore::new(config.related_concurrency.max(1)));
+
     let mut tasks = JoinSet::new();
-    while let Some(id) = artworks_pipeline.recv().await {
-        pb.inc_length(1);
-        if matches!(manager.lock().await.find_post(&id.url()), Ok(Some(_))) {
-            info!("[artwork] Skipping existing post: {}", id.url());
-            pb.inc(1);
-            continue;
-        }
-
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        let files_pipeline = files_pipeline.clone();
-        let sync_pipeline = sync_pipeline.clone();
-        let client = client.clone();
-        let pb = pb.clone();
-
-        let has_ffmpeg = config.has_ffmpeg;
-        tasks.spawn(async move {
-            let source = id.url();
-
-            let artwork = match client.fetch::<PixivArtwork>(&id.api_url()).await {
-                Ok(artwork) => artwork,
-                Err(e) => {
-                    error!("[artwork] Failed to fetch {source}: {e:?}");
-                    return;
+    let mut queue: Vec<(PixivArtworkId, u32)> = vec![];
+    // the same work may come from several sources (user, series, related)
+    let mut seen = HashSet::new();
+    let checkpoint = &config.checkpoint;
+    let mut receiving = true;
+    // excluded works are never fetched, as if already seen
+    seen.extend(config.exclude_ids.iter().flat_map(|&id| {
+        [PixivArtworkId::Illust(id), PixivArtworkId::Novel(id)]
+    }));
+    loop {
+        tokio::select! {
+            id = artworks_pipeline.recv(), if receiving => match id {
+                Some(id) => if seen.insert(id.clone()) {
+                    checkpoint.add(id.url());
+                    queue.push((id, 0));
+                },
+                None => receiving = false,
+            },
+            Some(related) = tasks.join_next() => {
+                let related: Vec<(PixivArtworkId, u32)> = related.unwrap_or_default();
+                for (id, depth) in related {
+                    if seen.insert(id.clone()) {
+                        checkpoint.add(id.url());
+                        queue.push((id, depth));
+                    }
+                }
+            },
+            else => break,
+        }
+
+        // after Ctrl+C, only the in-flight works are archived, the rest stays in the checkpoint
+        if config.shutdown.is_requested() {
+            queue.clear();
+        }
+
+        while let Some((id, depth)) = queue.pop() {
+            pb.inc_length(1);
+            let existing = {
+                let manager = manager.lock().await;
+                match manager.find_post(&id.url()) {
+                    Ok(Some(post)) => Some(manager.post_path(&config.output, post)),
+                    _ => None,
                 }
             };
+            // posts cut by an earlier --max-pages get the rest of their pages
+            let top_up = existing
+                .as_deref()
+                .is_some_and(|dir| common::is_truncated(dir, config.max_pages));
+            if existing.is_some() && !config.overwrite && !config.update && !top_up {
+                info!("[artwork] Skipping existing post: {}", id.url());
+                checkpoint.remove(&id.url());
+                pb.inc(1);
+                continue;
+            }
 
-            if !has_ffmpeg
-                && matches!(
-                    artwork.content,
-                    PixivArtworkContent::Illust {
-                        illust_type: IllustType::Ugoira,
-                        ..
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            let files_pipeline = files_pipeline.clone();
+            let sync_pipeline = sync_pipeline.clone();
+            let client = client.clone();
+            let pb = pb.clone();
+            let options = options.clone();
+            let related_semaphore = related_semaphore.clone();
+            let checkpoint = checkpoint.clone();
+            let failed = config.failed.clone();
+            let deleted = config.deleted.clone();
+            let hooks = config.hooks.clone();
+
+            tasks.spawn(async move {
+                let source = id.url();
+
+                let (artwork, raw) = match client
+                    .fetch_with_raw::<PixivArtwork>(&id.api_url())
+                    .await
+                {
+                    Ok(artwork) => artwork,
+                    // retrying is pointless, keep a tombstone instead
+                    Err(PixivError::Deleted(reason)) => {
+                        warn!("[artwork] {source} is deleted or restricted: {reason}");
+                        deleted.record(&source, &reason, Some(existing.is_some()));
+                        failed.remove(&source);
+                        checkpoint.remove(&source);
+                        pb.inc(1);
+                        return vec![];
+                    }
+                    Err(e) => {
+                        error!("[artwork] Failed to fetch {source}: {e:?}");
+                        failed.record(&source, e.kind());
+                        hooks.failed(&source, e.kind());
+                        return vec![];
+                    }
+                };
+
+                if let Some(reason) = options.filter(&artwork) {
+                    info!("[artwork] Skipping {source}: {reason}");
+                    checkpoint.remove(&source);
+                    pb.inc(1);
+                    return vec![];
+                }
+
+                let ((mut contents, mut thumb), (comments, comment_images), related) = join!(
+                    common::get_contents_and_thumb(
+                        &client,
+                        &artwork,
+                        &options,
+                        existing.as_deref(),
+                    ),
+                    common::get_comments(&client, &artwork, &options),
+                    async {
+                        if !options.related {
+                            return vec![];
+                        }
+                        let _permit = related_semaphore.acquire().await.unwrap();
+                        crate::related::fetch_related(&client, &id).await
                     }
-                )
-            {
-                warn!(
-                    "[artwork] Skipping Ugoira {} because ffmpeg is not found",
-                    artwork.id
                 );
-                return;
-            }
 
-            let ((contents, thumb), comments) = join!(
-                common::get_contents_and_thumb(&client, &artwork),
-                common::get_comments(&client, &artwork)
-            );
+                let pages = contents.len();
+                contents.extend(common::get_comment_images(comment_images, &options));
 
-            let files = contents
-                .iter()
-                .filter_map(|c| match c {
-                    UnsyncContent::File(f) => Some(f),
-                    UnsyncContent::Text(_) => None,
-                })
-                .chain(thumb.iter())
-                .map(|f| f.data.clone())
-                .collect::<Vec<_>>();
-
-            files_pipeline.send((files, tx)).unwrap();
-            sync_pipeline
-                .send(SyncEvent {
-                    source,
-                    artwork,
-                    contents,
-                    thumb,
-                    comments,
-                    files: rx,
-                })
-                .unwrap();
+                if let Some((format, quality)) = options.transcode {
+                    common::transcode_images(&mut contents, &mut thumb, format, quality);
+                }
+
+                // after transcoding, so the sidecars are named after the final files
+                if options.gallery_dl_metadata {
+                    let sidecars = common::gallery_dl_sidecars(&artwork, &contents[..pages]);
+                    contents.extend(sidecars.into_iter().map(UnsyncContent::File));
+                }
+
+                if options.plain {
+                    let sidecars =
+                        common::plain_sidecars(&source, &artwork, &contents[..pages], &comments);
+                    contents.extend(sidecars.into_iter().map(UnsyncContent::File));
+                }
+
+                if let Some(size) = options.thumbnails {
+                    common::add_thumbnails(&mut contents, &mut thumb, size);
+                }
+
+                if (options.update || top_up)
+                    && let Some(dir) = &existing
+                {
+                    common::reuse_existing_files(dir, &mut contents, &mut thumb);
+                }
+
+                if let Some(max) = options.max_file_size {
+                    common::skip_large_files(&client, &mut contents, &mut thumb, max).await;
+                }
+
+                // generated on every run, so update runs refresh the counts,
+                // the database keeps them instead in the other layouts
+                if options.plain {
+                    let stats = common::stats(&artwork);
+                    contents.push(UnsyncContent::File(json_sidecar("stats.json", &stats)));
+                }
+
+                if !related.is_empty() {
+                    let ids = related.iter().filter_map(|id| id.id()).collect::<Vec<_>>();
+                    contents.push(UnsyncContent::File(json_sidecar("related.json", &json!(ids))));
+                }
+
+                if options.raw_json {
+                    contents.push(UnsyncContent::File(gzip_sidecar("artwork.json.gz", raw)));
+                }
+
+                let files = contents
+                    .iter()
+                    .filter_map(|c| match c {
+                        UnsyncContent::File(f) => Some(f),
+                        UnsyncContent::Text(_) => None,
+                    })
+                    .chain(thumb.iter())
+                    .filter(|f| !matches!(f.data, ArchiveRequest::Existing(_)))
+                    .map(|f| f.data.clone())
+                    .collect::<Vec<_>>();
+
+                files_pipeline.send((files, tx)).unwrap();
+                sync_pipeline
+                    .send(SyncEvent {
+                        source,
+                        artwork,
+                        contents,
+                        thumb,
+                        comments,
+                        files: rx,
+                    })
+                    .unwrap();
+
+                pb.inc(1);
+
+                if depth < options.follow_related {
+                    related.into_iter().map(|id| (id, depth + 1)).collect()
+                } else {
+                    vec![]
+                }
+            });
+        }
+    }
 
-            pb.inc(1);
-        });
+    if let Err(e) = config.failed.save() {
+        error!("[artwork] Failed to save failed works: {e}");
+    }
+    if let Err(e) = config.deleted.save() {
+        error!("[artwork] Failed to save deleted works: {e}");
     }
 
-    tasks.join_all().await;
     info!("[artwork] Archive resolved");
 }
 
-pub async fn archive_artworks(mut sync_pipeline: Output<SyncEvent>, manager: &Manager) {
+struct PendingPost {
+    source: String,
+    artwork: PixivArtwork,
+    contents: Vec<UnsyncContent<ArchiveRequest>>,
+    thumb: Option<UnsyncFileMeta<ArchiveRequest>>,
+    comments: Vec<Comment>,
+    files_map: HashMap<String, StagedFile>,
+    author: AuthorId,
+}
+
+/// Downloaded file, hashed and moved to the output disk before the lock of the manager
+struct StagedFile {
+    temp: TempPath,
+    sha256: String,
+}
+
+pub async fn archive_artworks(
+    mut sync_pipeline: Output<SyncEvent>,
+    manager: &Manager,
+    config: &Config,
+    client: &PixivClient,
+) {
     let platform = manager
         .lock()
         .await
-        .import_platform("pixiv".to_string())
+        .import_platform("pixiv")
         .expect("Failed to get platform");
 
-    let mut user_manager = UserManager::new(platform);
+    if config.dedupe && let Err(e) = manager.lock().await.index_files() {
+        warn!("[artwork] Failed to index file hashes, deduplicating will be slow: {e}");
+    }
 
-    'main: while let Some(event) = sync_pipeline.recv().await {
-        let Ok(mut files_map) = event.files.await else {
-            error!("[artwork] Failed to archive files for {}", event.artwork.id);
-            continue;
-        };
+    // shared by the workers, authors are imported once under the lock of the manager
+    let user_manager = Mutex::new(UserManager::new(platform));
+    let series_seen = Mutex::new(HashSet::new());
+    let url_map = config.url_map.then(|| Mutex::new(UrlMap::open(&config.output)));
+    let sync_pipeline = tokio::sync::Mutex::new(sync_pipeline);
+
+    // posts are committed together in batches, a crash loses at most one uncommitted batch
+    let batch_size = config.batch_size.max(1);
+    let batch_interval = Duration::from_secs(config.batch_interval);
+
+    let remote = config.remote.as_ref();
+    let low_space = AtomicBool::new(false);
+    let worker = async || {
+        let mut closed = false;
+        while !closed {
+            let mut batch = Vec::with_capacity(batch_size);
+            let mut deadline = Instant::now() + batch_interval;
+            while batch.len() < batch_size {
+                let event = if batch.is_empty() {
+                    sync_pipeline.lock().await.recv().await
+                } else {
+                    let next = async { sync_pipeline.lock().await.recv().await };
+                    match timeout_at(deadline, next).await {
+                        Ok(event) => event,
+                        Err(_) => break,
+                    }
+                };
 
-        let Ok(author) = user_manager.import(&manager.lock().await, &event.artwork) else {
-            error!(
-                "[artwork] Failed to archive author for {}",
-                event.artwork.user_id
-            );
-            continue;
-        };
+                let Some(event) = event else {
+                    closed = true;
+                    break;
+                };
 
-        let mut manager = manager.lock().await;
-        let manager = manager.transaction().unwrap();
-        let files = match UnsyncPost::new(
-            platform,
-            event.source,
-            event.artwork.title.clone(),
-            event.contents,
-        )
-        .thumb(event.thumb)
-        .authors(vec![author])
-        .comments(event.comments)
-        .published(common::parse_date(&event.artwork.create_date))
-        .updated(common::parse_date(&event.artwork.upload_date))
-        .tags(event.artwork.tags.into_tags(platform))
-        .collections(common::get_collections(&event.artwork))
-        .sync(&manager)
-        {
-            Ok((_post, files_map)) => files_map,
-            Err(e) => {
+                let Ok(files_map) = event.files.await else {
+                    error!("[artwork] Failed to archive files for {}", event.artwork.id);
+                    config.failed.record(&event.source, "download");
+                    config.hooks.failed(&event.source, "download");
+                    continue;
+                };
+                // the slow part of saving, done while the other workers write to the manager
+                let files_map = match stage_files(files_map, config).await {
+                    Ok(files_map) => files_map,
+                    Err(e) => {
+                        error!("[artwork] Failed to stage files for {}: {e}", event.artwork.id);
+                        config.failed.record(&event.source, "save");
+                        config.hooks.failed(&event.source, "save");
+                        continue;
+                    }
+                };
+
+                let profile = match config.user_profiles {
+                    true => {
+                        // the first worker to reserve the user fetches it, the others wait for it
+                        let reserved = user_manager.lock().unwrap().profile(&event.artwork.user_id);
+                        let fetch = || async {
+                            let profile = fetch_profile(client, &event.artwork.user_id).await;
+                            if let Some((profile, raw)) = &profile {
+                                let raw = config.raw_json.then_some(raw.as_slice());
+                                let root = &config.output;
+                                save_profile(client, profile, raw, root, remote, manager).await;
+                            }
+                            profile.map(|(profile, _)| profile)
+                        };
+                        reserved.get_or_init(fetch).await.clone()
+                    }
+                    false => None,
+                };
+
+                let series = event
+                    .artwork
+                    .series_id()
+                    .filter(|series| series_seen.lock().unwrap().insert(*series));
+                if let Some(series) = series
+                    && let Some(detail) = fetch_series_detail(client, series).await
+                {
+                    save_series_detail(client, &detail, &config.output, remote, manager).await;
+                }
+
+                let author = {
+                    let manager = manager.lock().await;
+                    let mut user_manager = user_manager.lock().unwrap();
+                    user_manager.import(&**manager, &event.artwork, profile.as_ref())
+                };
+                let Ok(author) = author else {
+                    error!(
+                        "[artwork] Failed to archive author for {}",
+                        event.artwork.user_id
+                    );
+                    config.failed.record(&event.source, "author");
+                    config.hooks.failed(&event.source, "author");
+                    continue;
+                };
+
+                if batch.is_empty() {
+                    deadline = Instant::now() + batch_interval;
+                }
+                batch.push(PendingPost {
+                    source: event.source,
+                    artwork: event.artwork,
+                    contents: event.contents,
+                    thumb: event.thumb,
+                    comments: event.comments,
+                    files_map,
+                    author,
+                });
+            }
+
+            if batch.is_empty() {
+                continue;
+            }
+
+            // the posts left out stay pending in the checkpoint, for --resume
+            if low_space.load(Ordering::Relaxed) {
+                continue;
+            }
+            if !disk::has_space(&config.output, config.min_free_space) {
+                if !low_space.swap(true, Ordering::Relaxed) {
+                    error!(
+                        "[artwork] Less than {} free on the output disk, stopping. Free some space and run again with --resume",
+                        disk::format_size(config.min_free_space)
+                    );
+                    config.shutdown.request();
+                }
+                continue;
+            }
+
+            let manager = manager.lock().await;
+            let storage: &dyn Storage = &**manager;
+            if let Err(e) = storage.begin() {
+                error!("[artwork] Failed to begin a batch of {} posts: {e:?}", batch.len());
+                for pending in &batch {
+                    config.failed.record(&pending.source, "archive");
+                    config.hooks.failed(&pending.source, "archive");
+                }
+                continue;
+            }
+            let mut archived = vec![];
+            for mut pending in batch {
+                // a failed post is rolled back alone, without dropping the rest of the batch
+                storage.begin_post().unwrap();
+                let result = 'post: {
+                    let (published, updated) = common::parse_dates(&pending.artwork);
+                    let mut tags = pending
+                        .artwork
+                        .tags
+                        .into_tags(platform, config.tag_language.as_deref());
+                    tags.extend(common::ai_tags(pending.artwork.ai_type, config.tag_ai, platform));
+
+                    let post = UnsyncPost::new(
+                        platform,
+                        pending.source.clone(),
+                        pending.artwork.title.clone(),
+                        pending.contents,
+                    )
+                    .thumb(pending.thumb)
+                    .authors(vec![pending.author])
+                    .comments(pending.comments)
+                    .published(published)
+                    .updated(updated)
+                    .tags(tags)
+                    .collections(common::get_collections(&pending.artwork));
+                    let (post, files) = match storage.sync_post(post) {
+                        Ok(synced) => synced,
+                        Err(e) => {
+                            error!(
+                                "[artwork] Failed to archive post for {}: {:?}",
+                                pending.artwork.id, e
+                            );
+                            config.failed.record(&pending.source, "archive");
+                            config.hooks.failed(&pending.source, "archive");
+                            break 'post None;
+                        }
+                    };
+
+                    // refreshed on every run, the plain layout has them as `stats.json`
+                    let stats = common::stats(&pending.artwork);
+                    if let Err(e) = storage.set_metadata(&Metadata::Stats(post), &stats) {
+                        warn!("[artwork] Failed to record stats of {}: {e}", pending.artwork.id);
+                    }
+
+                    // uploaded files have no folder to create
+                    if config.remote.is_none()
+                        && let Some(path) = files.first().map(|(dst, _)| dst.parent().unwrap())
+                        && let Err(e) = create_dir_all(path).await
+                    {
+                        error!(
+                            "[artwork] Failed to create directory for {}: {}",
+                            path.display(),
+                            e
+                        );
+                        config.failed.record(&pending.source, "save");
+                        config.hooks.failed(&pending.source, "save");
+                        break 'post None;
+                    }
+
+                    let mut saved = vec![];
+                    for (path, req) in files {
+                        if matches!(req, ArchiveRequest::Existing(_)) {
+                            continue;
+                        }
+
+                        let key = req.key();
+                        // read from the staged file, the saved one may be in the bucket
+                        let size = match (&req, config.resize) {
+                            (ArchiveRequest::ImageWithSize { .. }, ResizeMode::Metadata) => {
+                                pending
+                                    .files_map
+                                    .get(&key)
+                                    .and_then(|staged| image::image_dimensions(&staged.temp).ok())
+                            }
+                            _ => None,
+                        };
+                        let duplicate = |sha256: &str| match config.dedupe {
+                            true => find_duplicate(storage, &config.output, sha256),
+                            false => None,
+                        };
+                        let saved_file =
+                            save_file(&mut pending.files_map, &path, &key, duplicate, remote);
+                        let sha256 = match saved_file.await {
+                            Ok(sha256) => sha256,
+                            Err(e) => {
+                                error!("[artwork] Failed to save file {}: {}", path.display(), e);
+                                config.failed.record(&pending.source, "save");
+                                config.hooks.failed(&pending.source, "save");
+                                break 'post None;
+                            }
+                        };
+                        if let Err(e) = record_hash(storage, post, &path, &sha256) {
+                            warn!("[artwork] Failed to record hash of {}: {e}", path.display());
+                        }
+                        if let ArchiveRequest::ImageWithSize { width, height, .. } = req
+                            && let Some(size) = size
+                            && size != (width, height)
+                            && let Err(e) = record_size(storage, post, &path, size)
+                        {
+                            warn!("[artwork] Failed to record size of {}: {e}", path.display());
+                        }
+                        saved.push((key, path));
+                    }
+
+                    Some((post, saved))
+                };
+
+                match result {
+                    Some((post, saved)) => {
+                        storage.end_post(true).unwrap();
+                        archived.push((pending.source, pending.artwork, post, saved));
+                    }
+                    None => storage.end_post(false).unwrap(),
+                }
+            }
+
+            if let Err(e) = storage.commit() {
                 error!(
-                    "[artwork] Failed to archive post for {}: {:?}",
-                    event.artwork.id, e
+                    "[artwork] Failed to commit transaction of {} posts: {e:?}",
+                    archived.len()
                 );
+                for (source, ..) in &archived {
+                    config.failed.record(source, "archive");
+                    config.hooks.failed(source, "archive");
+                }
                 continue;
             }
-        };
 
-        if let Some(path) = files.first().map(|(dst, _)| dst.parent().unwrap())
-            && let Err(e) = create_dir_all(path).await
-        {
-            error!(
-                "[artwork] Failed to create directory for {}: {}",
-                path.display(),
-                e
-            );
-            continue;
-        }
+            for (source, artwork, post, saved) in archived {
+                config.checkpoint.remove(&source);
+                config.failed.remove(&source);
+                config.deleted.remove(&source);
+                if let Some(series) = artwork.series_id()
+                    && let Some(nav) = &artwork.series_nav_data
+                    && nav.order > 0
+                {
+                    record_series_order(storage, &config.output, remote, series, nav.order, &source)
+                        .await;
+                }
+
+                let dir = storage.post_path(&config.output, post);
+                config.hooks.archived(&source, &artwork, &dir);
+
+                if let Some(url_map) = &url_map {
+                    let mut url_map = url_map.lock().unwrap();
+                    url_map.insert(source, &dir);
+                    for (url, path) in saved {
+                        url_map.insert(url, &path);
+                    }
+                }
+
+                info!("[artwork] Archived {} ({})", artwork.title, artwork.id);
+            }
+
+            if let Some(url_map) = &url_map
+                && let Err(e) = url_map.lock().unwrap().save()
+            {
+                error!("[artwork] Failed to save url map: {e}");
+            }
 
-        for (path, req) in files {
-            let url = req.url().to_string();
-            if let Err(e) = save_file(&mut files_map, &path, &url).await {
-                error!("[artwork] Failed to save file {}: {}", path.display(), e);
-                continue 'main;
+            if let Err(e) = config.checkpoint.save() {
+                error!("[artwork] Failed to save checkpoint: {e}");
+            }
+            if let Err(e) = config.failed.save() {
+                error!("[artwork] Failed to save failed works: {e}");
+            }
+            if let Err(e) = config.deleted.save() {
+                error!("[artwork] Failed to save deleted works: {e}");
+            }
+        }
+    };
+    let workers = (0..config.sync_workers.max(1)).map(|_| worker());
+    futures::future::join_all(workers).await;
+
+    // every listed work went through the workers by now
+    config
+        .resolved_users
+        .save(&**manager.lock().await, &config.checkpoint, &config.failed);
+
+    /// Hash the downloaded files and move them to the output disk, so saving them under the
+    /// lock of the manager is a rename instead of a copy
+    async fn stage_files(
+        files_map: HashMap<String, TempPath>,
+        config: &Config,
+    ) -> io::Result<HashMap<String, StagedFile>> {
+        let mut staged = HashMap::with_capacity(files_map.len());
+        for (url, temp) in files_map {
+            let sha256 = hash_file(&temp).await?;
+            // uploads stream from the temp file wherever it is
+            let temp = match config.remote {
+                Some(_) => temp,
+                None => stage(temp, &config.output).await?,
             };
+            staged.insert(url, StagedFile { temp, sha256 });
         }
+        Ok(staged)
+    }
 
-        if let Err(e) = manager.commit() {
-            error!(
-                "[artwork] Failed to commit transaction for {}: {e:?}",
-                event.artwork.id
-            );
-            continue;
+    async fn stage(temp: TempPath, root: &Path) -> io::Result<TempPath> {
+        let staged = tempfile::Builder::new()
+            .prefix(".staging-")
+            .tempfile_in(root)?
+            .into_temp_path();
+        // a rename is enough when the temp dir is on the same filesystem (see `--temp-dir`)
+        if let Err(e) = temp.persist(&staged) {
+            tokio::fs::copy(&e.path, &staged).await?;
         }
-        info!(
-            "[artwork] Archived {} ({})",
-            event.artwork.title, event.artwork.id
-        );
+        Ok(staged)
     }
 
+    /// Move the staged file to the archive, returning its SHA-256.
+    /// When `duplicate` finds an identical file already archived, it is hardlinked instead,
+    /// with a `remote` it is uploaded to the bucket.
     async fn save_file(
-        file_map: &mut HashMap<String, TempPath>,
+        file_map: &mut HashMap<String, StagedFile>,
         path: &PathBuf,
         url: &str,
-    ) -> Result<()> {
-        let temp = file_map.remove(url).ok_or(io::Error::new(
+        duplicate: impl FnOnce(&str) -> Option<PathBuf>,
+        remote: Option<&Remote>,
+    ) -> Result<String> {
+        let StagedFile { temp, sha256 } = file_map.remove(url).ok_or(io::Error::new(
             io::ErrorKind::NotFound,
             format!("File not found in map: {url}"),
         ))?;
 
+        if let Some(remote) = remote {
+            remote.upload(&temp, path).await?;
+            trace!("File uploaded: {url} -> {}", path.display());
+            return Ok(sha256);
+        }
+
+        if let Some(existing) = duplicate(&sha256)
+            && existing != *path
+        {
+            let _ = tokio::fs::remove_file(path).await;
+            match tokio::fs::hard_link(&existing, path).await {
+                Ok(()) => {
+                    trace!("File linked: {url} -> {}", existing.display());
+                    return Ok(sha256);
+                }
+                Err(e) => warn!(
+                    "[artwork] Failed to link {} to {}, copying instead: {e}",
+                    path.display(),
+                    existing.display()
+                ),
+            }
+        }
+
+        // staged on the output disk, so a rename unless the folder is on another filesystem
+        let temp = match temp.persist(path) {
+            Ok(()) => {
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    let permissions = std::fs::Permissions::from_mode(0o644);
+                    tokio::fs::set_permissions(path, permissions).await?;
+                }
+                trace!("File moved: {url} -> {}", path.display());
+                return Ok(sha256);
+            }
+            Err(e) => e.path,
+        };
+
+        // the destination may be hardlinked to other posts by --dedupe, writing into it
+        // with --overwrite would change them too, so the copy goes to a new file
+        match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e)?,
+            _ => {}
+        }
         let mut open_options = OpenOptions::new();
         let (mut src, mut dst) = try_join!(
             File::open(&temp),
-            open_options
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .open(&path)
+            open_options.write(true).create_new(true).open(&path)
         )?;
 
         io::copy(&mut src, &mut dst).await?;
         trace!("File saved: {url} -> {}", path.display());
 
-        Ok(())
+        Ok(sha256)
+    }
+
+    async fn hash_file(path: &Path) -> io::Result<String> {
+        let mut file = File::open(path).await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Archived file with the same SHA-256, if it is still on disk
+    fn find_duplicate(storage: &dyn Storage, root: &Path, sha256: &str) -> Option<PathBuf> {
+        let (post, filename) = storage.find_file(sha256)?;
+        let path = storage.post_path(root, post).join(filename);
+        path.exists().then_some(path)
+    }
+
+    /// Keep the SHA-256 in the extra of the file meta, for `verify --hashes`
+    fn record_hash(storage: &dyn Storage, post: PostId, path: &Path, sha256: &str) -> Result<()> {
+        let filename = path.file_name().unwrap_or_default().to_string_lossy();
+        storage.set_file_extra(post, &filename, "sha256", json!(sha256))
+    }
+
+    /// Keep the real size in the extra of the file meta, for images not the size reported by Pixiv
+    fn record_size(
+        storage: &dyn Storage,
+        post: PostId,
+        path: &Path,
+        (width, height): (u32, u32),
+    ) -> Result<()> {
+        let filename = path.file_name().unwrap_or_default().to_string_lossy();
+        storage.set_file_extra(post, &filename, "width", json!(width))?;
+        storage.set_file_extra(post, &filename, "height", json!(height))
     }
 
     info!("[artwork] Archive finished");
@@ -394,15 +1136,8 @@ mod common {
                     && let Some(href) = attrs
                         .iter_mut()
                         .find(|attr| attr.name.local.to_string() == "href")
-                        .filter(|attr| attr.value.to_string().starts_with("/jump.php?"))
                 {
-                    const PREFIX: usize = "/jump.php?".len();
-                    let url = &href.value[PREFIX..];
-                    let decoded = percent_encoding::percent_decode_str(url)
-                        .decode_utf8()
-                        .unwrap();
-
-                    href.value = decoded.to_string().into();
+                    href.value = canonical_link(&href.value).into();
                 };
                 self.0.handle(tag, printer);
             }
@@ -431,24 +1166,346 @@ mod common {
         vec![UnsyncContent::Text(markdown)]
     }
 
+    /// Unwrap `jump.php` redirects and turn pixiv links (relative, legacy or app) into canonical urls
+    fn canonical_link(href: &str) -> String {
+        let href = href.strip_prefix("https://www.pixiv.net").unwrap_or(href);
+        if let Some(target) = href.strip_prefix("/jump.php?") {
+            let target = target.strip_prefix("url=").unwrap_or(target);
+            return percent_encoding::percent_decode_str(target)
+                .decode_utf8()
+                .map(|target| target.to_string())
+                .unwrap_or_else(|_| target.to_string());
+        }
+
+        let Ok(url) = Url::parse("https://www.pixiv.net/").and_then(|base| base.join(href)) else {
+            return href.to_string();
+        };
+
+        let artwork = |id: &str| format!("https://www.pixiv.net/artworks/{id}");
+        let user = |id: &str| format!("https://www.pixiv.net/users/{id}");
+        let novel = |id: &str| format!("https://www.pixiv.net/novel/show.php?id={id}");
+
+        // links of the pixiv app
+        if url.scheme() == "pixiv" {
+            let id = url.path().trim_start_matches('/');
+            return match url.host_str() {
+                Some("illusts") => artwork(id),
+                Some("users") => user(id),
+                Some("novels") => novel(id),
+                _ => url.to_string(),
+            };
+        }
+
+        if !matches!(url.host_str(), Some("www.pixiv.net" | "pixiv.net")) {
+            return url.to_string();
+        }
+
+        let query = |key: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.to_string())
+        };
+        let segments = url
+            .path_segments()
+            .map(|segments| segments.filter(|s| !s.is_empty()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        // localized paths, e.g. `/en/artworks/1`
+        let segments = match segments.first() {
+            Some(&"en") => &segments[1..],
+            _ => &segments[..],
+        };
+
+        match segments {
+            ["member.php"] => query("id").map(|id| user(&id)),
+            ["member_illust.php"] => query("illust_id")
+                .map(|id| artwork(&id))
+                .or_else(|| query("id").map(|id| user(&id))),
+            ["artworks", id] | ["i", id] => Some(artwork(id)),
+            ["users", id, ..] => Some(user(id)),
+            ["novel", "show.php"] => query("id").map(|id| novel(&id)),
+            _ => None,
+        }
+        .unwrap_or_else(|| url.to_string())
+    }
+
     pub fn parse_date(date: &str) -> DateTime<Utc> {
         DateTime::parse_from_rfc3339(date).unwrap().to_utc()
     }
 
-    pub async fn get_comments(client: &PixivClient, artwork: &PixivArtwork) -> Vec<Comment> {
-        if artwork.has_comment() {
+    /// Published and updated dates, clamped so that updated is never before published
+    pub fn parse_dates(artwork: &PixivArtwork) -> (DateTime<Utc>, DateTime<Utc>) {
+        let published = parse_date(&artwork.create_date);
+        let updated = parse_date(&artwork.upload_date);
+        if updated < published {
+            warn!(
+                "[artwork] Upload date of {} is before its create date ({} < {}), clamping",
+                artwork.id, artwork.upload_date, artwork.create_date
+            );
+        }
+        clamp_dates(published, updated)
+    }
+
+    /// Tags of an AI-generated work, `ai-generated` with `--tag-ai`
+    pub fn ai_tags(ai_type: AiType, tag_ai: bool, platform: PlatformId) -> Vec<UnsyncTag> {
+        if ai_type != AiType::Yes {
+            return vec![];
+        }
+
+        // same label as shown on pixiv
+        let mut tags = vec![UnsyncTag {
+            name: "AI生成".to_string(),
+            platform: Some(platform),
+        }];
+        if tag_ai {
+            tags.push(UnsyncTag {
+                name: "ai-generated".to_string(),
+                platform: None,
+            });
+        }
+        tags
+    }
+
+    /// Updated date never before the published one
+    pub fn clamp_dates(
+        published: DateTime<Utc>,
+        updated: DateTime<Utc>,
+    ) -> (DateTime<Utc>, DateTime<Utc>) {
+        (published, updated.max(published))
+    }
+
+    /// `{file}.json` of every page, in the format of gallery-dl's `--write-metadata`
+    pub fn gallery_dl_sidecars(
+        artwork: &PixivArtwork,
+        contents: &[UnsyncContent<ArchiveRequest>],
+    ) -> Vec<UnsyncFileMeta<ArchiveRequest>> {
+        let PixivArtworkContent::Illust { illust_type, .. } = &artwork.content else {
+            return vec![];
+        };
+        let kind = match illust_type {
+            IllustType::Illust => "illust",
+            IllustType::Manga => "manga",
+            IllustType::Ugoira => "ugoira",
+        };
+        let rating = match artwork.x_restrict {
+            ContentRestrict::General => "General",
+            ContentRestrict::R18 => "R-18",
+            ContentRestrict::R18G => "R-18G",
+        };
+        let (published, _) = parse_dates(artwork);
+
+        contents
+            .iter()
+            .filter_map(|content| match content {
+                UnsyncContent::File(file) => Some(file),
+                UnsyncContent::Text(_) => None,
+            })
+            // the ugoira zip and the sidecars are not pages
+            .filter(|file| {
+                !matches!(
+                    file.data,
+                    ArchiveRequest::Original(_)
+                        | ArchiveRequest::Sidecar { .. }
+                        | ArchiveRequest::Gzip { .. }
+                )
+            })
+            .enumerate()
+            .map(|(num, file)| {
+                let (filename, extension) = file
+                    .filename
+                    .rsplit_once('.')
+                    .unwrap_or((file.filename.as_str(), ""));
+                let url = match &file.data {
+                    ArchiveRequest::Existing(_) => None,
+                    request => Some(request.url()),
+                };
+                json_sidecar(
+                    &format!("{}.json", file.filename),
+                    &json!({
+                        "category": "pixiv",
+                        "subcategory": "work",
+                        "id": artwork.id.parse::<u64>().ok(),
+                        "title": artwork.title,
+                        "caption": artwork.description,
+                        "type": kind,
+                        "user": {
+                            "id": artwork.user_id.parse::<u64>().ok(),
+                            "name": artwork.user_name,
+                            "account": artwork.user_account,
+                        },
+                        "tags": artwork.tags.tags.iter().map(|tag| &tag.tag).collect::<Vec<_>>(),
+                        "create_date": artwork.create_date,
+                        "date": published.format("%Y-%m-%d %H:%M:%S").to_string(),
+                        "page_count": artwork.page_count,
+                        "width": file.extra.get("width"),
+                        "height": file.extra.get("height"),
+                        "total_view": artwork.view_count,
+                        "total_bookmarks": artwork.bookmark_count,
+                        "x_restrict": artwork.x_restrict as u8,
+                        "rating": rating,
+                        "illust_ai_type": artwork.ai_type as u8,
+                        "num": num,
+                        "url": url,
+                        "filename": filename,
+                        "extension": extension,
+                    }),
+                )
+            })
+            .collect()
+    }
+
+    /// `metadata.json`, `comments.json` and the `novel.md` of novels, for `--layout plain`
+    pub fn plain_sidecars(
+        source: &str,
+        artwork: &PixivArtwork,
+        contents: &[UnsyncContent<ArchiveRequest>],
+        comments: &[Comment],
+    ) -> Vec<UnsyncFileMeta<ArchiveRequest>> {
+        let kind = match &artwork.content {
+            PixivArtworkContent::Illust { illust_type, .. } => match illust_type {
+                IllustType::Illust => "illust",
+                IllustType::Manga => "manga",
+                IllustType::Ugoira => "ugoira",
+            },
+            PixivArtworkContent::Novel { .. } => "novel",
+        };
+        let (published, updated) = parse_dates(artwork);
+
+        let mut sidecars = vec![json_sidecar(
+            crate::plain::METADATA,
+            &json!({
+                "url": source,
+                "id": artwork.id.parse::<u64>().ok(),
+                "title": artwork.title,
+                "type": kind,
+                "user": {
+                    "id": artwork.user_id.parse::<u64>().ok(),
+                    "name": artwork.user_name,
+                    "account": artwork.user_account,
+                },
+                "tags": artwork.tags.tags.iter().map(|tag| &tag.tag).collect::<Vec<_>>(),
+                "description": artwork.description,
+                "published": published.to_rfc3339(),
+                "updated": updated.to_rfc3339(),
+                "page_count": artwork.page_count,
+                "bookmarks": artwork.bookmark_count,
+                "likes": artwork.like_count,
+                "views": artwork.view_count,
+                "x_restrict": artwork.x_restrict as u8,
+                "ai_type": artwork.ai_type as u8,
+            }),
+        )];
+
+        if !comments.is_empty() {
+            sidecars.push(json_sidecar("comments.json", &json!(comments)));
+        }
+
+        if let PixivArtworkContent::Novel { .. } = &artwork.content {
+            // the description comes first, it is already in the metadata
+            let skip = parse_description(artwork).len();
+            let text = contents
+                .iter()
+                .skip(skip)
+                .map(|content| match content {
+                    UnsyncContent::Text(text) => text.clone(),
+                    UnsyncContent::File(file) => format!("![]({})", file.filename),
+                })
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            sidecars.push(UnsyncFileMeta::new(
+                "novel.md".to_string(),
+                "text/markdown".to_string(),
+                ArchiveRequest::Sidecar {
+                    name: "novel.md".to_string(),
+                    content: text,
+                },
+            ));
+        }
+
+        sidecars
+    }
+
+    pub async fn get_comments(
+        client: &PixivClient,
+        artwork: &PixivArtwork,
+        options: &ArtworkOptions,
+    ) -> (Vec<Comment>, BTreeSet<CommentImage>) {
+        if options.comments && artwork.has_comment() {
             crate::comment::get_comments(
                 client,
                 &artwork.id,
                 matches!(artwork.content, PixivArtworkContent::Novel { .. }),
-                true,
+                CommentOptions {
+                    max_comments: options.max_comments,
+                    emoji: options.comment_emoji,
+                    avatar_size: options.avatar_size,
+                },
             )
             .await
         } else {
-            vec![]
+            Default::default()
         }
     }
 
+    /// Stamps, emoji and avatars used in the comments, attached to the post when enabled.
+    /// Avatars are listed by commenter id in `comment_avatars.json`, names are not unique.
+    pub fn get_comment_images(
+        images: BTreeSet<CommentImage>,
+        options: &ArtworkOptions,
+    ) -> Vec<UnsyncContent<ArchiveRequest>> {
+        let images = images
+            .into_iter()
+            .filter(|image| match image {
+                CommentImage::Stamp(_) => options.comment_stamps,
+                CommentImage::Emoji(..) => options.comment_emoji == CommentEmoji::Image,
+                CommentImage::Avatar { .. } => options.comment_avatars,
+            })
+            .collect::<Vec<_>>();
+
+        let avatars = images
+            .iter()
+            .filter_map(|image| match image {
+                CommentImage::Avatar {
+                    user_id, user_name, ..
+                } => Some((
+                    user_id,
+                    json!({ "name": user_name, "file": image.filename() }),
+                )),
+                _ => None,
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let mut contents = images
+            .iter()
+            .map(|image| {
+                let filename = image.filename();
+                let mime = mime_guess::from_path(&filename).first_or_octet_stream();
+                UnsyncContent::File(UnsyncFileMeta::new(
+                    filename,
+                    mime.to_string(),
+                    image.request(),
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        if !avatars.is_empty() {
+            contents.push(UnsyncContent::File(json_sidecar(
+                "comment_avatars.json",
+                &json!(avatars),
+            )));
+        }
+        contents
+    }
+
+    /// Counts of the work, see [`Metadata::Stats`]
+    pub fn stats(artwork: &PixivArtwork) -> Value {
+        json!({
+            "bookmarks": artwork.bookmark_count,
+            "likes": artwork.like_count,
+            "views": artwork.view_count,
+            "comments": artwork.comment_count,
+        })
+    }
+
     pub fn get_collections(artwork: &PixivArtwork) -> Vec<UnsyncCollection> {
         // TODO: add more collections support
         artwork
@@ -459,35 +1516,263 @@ mod common {
             .collect()
     }
 
+    /// Convert the png and jpeg downloads, renaming them to the new format
+    pub fn transcode_images(
+        contents: &mut [UnsyncContent<ArchiveRequest>],
+        thumb: &mut Option<UnsyncFileMeta<ArchiveRequest>>,
+        format: TranscodeFormat,
+        quality: u8,
+    ) {
+        let files = contents
+            .iter_mut()
+            .filter_map(|content| match content {
+                UnsyncContent::File(file) => Some(file),
+                UnsyncContent::Text(_) => None,
+            })
+            .chain(thumb.iter_mut());
+
+        for file in files {
+            let ArchiveRequest::Image(url) = &file.data else {
+                continue;
+            };
+            if !matches!(file.mime.as_str(), "image/png" | "image/jpeg") {
+                continue;
+            }
+
+            let stem = file
+                .filename
+                .rsplit_once('.')
+                .map_or(file.filename.as_str(), |(stem, _)| stem);
+            file.filename = format!("{stem}.{}", format.extension());
+            file.mime = format.mime().to_string();
+            file.data = ArchiveRequest::Transcode {
+                url: url.clone(),
+                format,
+                quality,
+            };
+        }
+    }
+
+    /// Downscaled companions of the illust pages, the one of the first page becoming the post thumb
+    pub fn add_thumbnails(
+        contents: &mut Vec<UnsyncContent<ArchiveRequest>>,
+        thumb: &mut Option<UnsyncFileMeta<ArchiveRequest>>,
+        size: u32,
+    ) {
+        let thumbnails = contents
+            .iter()
+            .filter_map(|content| match content {
+                UnsyncContent::File(file) => thumbnail_of(file, size),
+                UnsyncContent::Text(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(first) = thumb.as_ref().and_then(|thumb| thumbnail_of(thumb, size)) {
+            *thumb = Some(first);
+        }
+        contents.extend(thumbnails.into_iter().map(UnsyncContent::File));
+    }
+
+    fn thumbnail_of(
+        file: &UnsyncFileMeta<ArchiveRequest>,
+        size: u32,
+    ) -> Option<UnsyncFileMeta<ArchiveRequest>> {
+        let stem = file
+            .filename
+            .rsplit_once('.')
+            .map_or(file.filename.as_str(), |(stem, _)| stem);
+        let filename = format!("{stem}_thumb.jpg");
+
+        let request = match &file.data {
+            ArchiveRequest::Image(url)
+            | ArchiveRequest::Transcode { url, .. }
+            | ArchiveRequest::ImageWithFallback { url, .. }
+                if is_page(url) =>
+            {
+                // same url as the page, so it is downscaled from the downloaded page
+                ArchiveRequest::Thumbnail {
+                    url: url.clone(),
+                    size,
+                }
+            }
+            // pages reused by --update keep the thumbnail made the first time
+            ArchiveRequest::Existing(path) => {
+                let path = Path::new(path).with_file_name(&filename);
+                if !path.exists() {
+                    return None;
+                }
+                ArchiveRequest::Existing(path.display().to_string())
+            }
+            _ => return None,
+        };
+
+        Some(
+            UnsyncFileMeta::new(filename, "image/jpeg".to_string(), request).extra(
+                HashMap::from([("thumbnail_of".to_string(), json!(file.filename))]),
+            ),
+        )
+    }
+
+    /// Whether the url is an illust page, other images get no thumbnail
+    fn is_page(url: &str) -> bool {
+        url.contains("/img-original/") || url.contains("/img-master/")
+    }
+
+    /// Sidecar of the illusts cut by `--max-pages`
+    pub const TRUNCATED: &str = "truncated.json";
+
+    /// Whether the post has fewer pages than a run with `max_pages` would archive
+    pub fn is_truncated(dir: &Path, max_pages: Option<usize>) -> bool {
+        let Ok(content) = std::fs::read_to_string(dir.join(TRUNCATED)) else {
+            return false;
+        };
+        let Ok(value) = serde_json::from_str::<serde_json::Value>(&content) else {
+            return false;
+        };
+
+        let count = |key: &str| value[key].as_u64().unwrap_or_default() as usize;
+        count("pages") < count("total").min(max_pages.unwrap_or(usize::MAX).max(1))
+    }
+
+    /// Point downloads at files already in the post folder, so updates only refresh metadata
+    pub fn reuse_existing_files(
+        dir: &Path,
+        contents: &mut [UnsyncContent<ArchiveRequest>],
+        thumb: &mut Option<UnsyncFileMeta<ArchiveRequest>>,
+    ) {
+        let files = contents
+            .iter_mut()
+            .filter_map(|content| match content {
+                UnsyncContent::File(file) => Some(file),
+                UnsyncContent::Text(_) => None,
+            })
+            .chain(thumb.iter_mut());
+
+        for file in files {
+            // generated files are cheap and may have changed
+            if matches!(
+                file.data,
+                ArchiveRequest::Sidecar { .. } | ArchiveRequest::Gzip { .. }
+            ) {
+                continue;
+            }
+
+            let path = dir.join(&file.filename);
+            if path.metadata().is_ok_and(|metadata| metadata.len() > 0) {
+                trace!("[artwork] Reusing {}", path.display());
+                file.data = ArchiveRequest::Existing(path.display().to_string());
+            }
+        }
+    }
+
+    /// Replace the files larger than `max` bytes by a note, by their Content-Length
+    pub async fn skip_large_files(
+        client: &PixivClient,
+        contents: &mut [UnsyncContent<ArchiveRequest>],
+        thumb: &mut Option<UnsyncFileMeta<ArchiveRequest>>,
+        max: u64,
+    ) {
+        let mut sizes = HashMap::new();
+        let mut is_large = async |file: &UnsyncFileMeta<ArchiveRequest>| {
+            if matches!(
+                file.data,
+                ArchiveRequest::Sidecar { .. }
+                    | ArchiveRequest::Gzip { .. }
+                    | ArchiveRequest::Existing(_)
+            ) {
+                return None;
+            }
+
+            let url = file.data.url();
+            if let Some(size) = sizes.get(url) {
+                return *size;
+            }
+            let size = match client.content_length(url).await {
+                Ok(Some(size)) if size > max => Some(size),
+                Ok(_) => None,
+                Err(e) => {
+                    warn!("[artwork] Failed to check size of {url}: {e}");
+                    None
+                }
+            };
+            sizes.insert(url.to_string(), size);
+            size
+        };
+
+        for content in contents.iter_mut() {
+            let UnsyncContent::File(file) = content else {
+                continue;
+            };
+            if let Some(size) = is_large(file).await {
+                warn!(
+                    "[artwork] Skipping {}, {size} bytes is above --max-file-size",
+                    file.data.url()
+                );
+                let note = format!(
+                    "[{} skipped: {size} bytes, above the limit of {max} bytes]",
+                    file.filename
+                );
+                *content = UnsyncContent::Text(note);
+            }
+        }
+
+        if let Some(file) = thumb.as_ref()
+            && is_large(file).await.is_some()
+        {
+            *thumb = None;
+        }
+    }
+
     pub async fn get_contents_and_thumb(
         client: &PixivClient,
         artwork: &PixivArtwork,
+        options: &ArtworkOptions,
+        existing: Option<&Path>,
     ) -> (
         Vec<UnsyncContent<ArchiveRequest>>,
         Option<UnsyncFileMeta<ArchiveRequest>>,
     ) {
         let mut contents = common::parse_description(artwork);
-        let thumb: Option<UnsyncFileMeta<ArchiveRequest>>;
+        let mut thumb: Option<UnsyncFileMeta<ArchiveRequest>>;
 
         match &artwork.content {
             PixivArtworkContent::Illust { illust_type, .. } => {
-                let file_metas = match illust::fetch_pages(client, &artwork.id).await {
-                    Ok(artworks) => artworks,
-                    Err(e) => {
-                        error!("[artwork] Failed to fetch pages {}: {:?}", artwork.id, e);
-                        return (vec![], None);
+                let existing = existing
+                    .filter(|_| *illust_type != IllustType::Ugoira)
+                    .and_then(|dir| illust::existing_pages(dir, artwork, options));
+                let file_metas = match existing {
+                    Some(file_metas) => {
+                        info!("[artwork] Reusing archived pages of {}", artwork.id);
+                        file_metas
                     }
+                    None => match illust::fetch_pages(client, artwork, options).await {
+                        Ok(artworks) => artworks,
+                        Err(e) => {
+                            error!("[artwork] Failed to fetch pages {}: {:?}", artwork.id, e);
+                            return (vec![], None);
+                        }
+                    },
                 };
                 thumb = file_metas.first().cloned();
 
                 match illust_type {
                     IllustType::Illust | IllustType::Manga => {
+                        let total = artwork.page_count as usize;
+                        // kept after a top-up too, so the post is no longer seen as truncated
+                        if file_metas.len() < total
+                            || existing.is_some_and(|dir| dir.join(TRUNCATED).exists())
+                        {
+                            contents.push(UnsyncContent::File(json_sidecar(
+                                TRUNCATED,
+                                &json!({ "pages": file_metas.len(), "total": total }),
+                            )));
+                        }
                         contents.extend(file_metas.into_iter().map(UnsyncContent::File));
                     }
                     IllustType::Ugoira => {
                         let extra = thumb.as_ref().unwrap().extra.clone();
-                        let ugoira = match client
-                            .fetch::<PixivUgoira>(&format!(
+                        let (ugoira, raw) = match client
+                            .fetch_with_raw::<PixivUgoira>(&format!(
                                 "https://www.pixiv.net/ajax/illust/{}/ugoira_meta",
                                 &artwork.id
                             ))
@@ -500,13 +1785,48 @@ mod common {
                             }
                         };
 
+                        if options.ugoira_poster && let Some(first) = ugoira.frames.first() {
+                            let ext = mime2ext::mime2ext(&ugoira.mime_type).unwrap_or("jpg");
+                            thumb = Some(
+                                UnsyncFileMeta::new(
+                                    format!("poster.{ext}"),
+                                    ugoira.mime_type.clone(),
+                                    ArchiveRequest::UgoiraPoster {
+                                        url: ugoira.original_src.clone(),
+                                        frame: first.file.clone(),
+                                    },
+                                )
+                                .extra(extra.clone()),
+                            );
+                        }
+
+                        if options.raw_json {
+                            contents.push(UnsyncContent::File(gzip_sidecar(
+                                "ugoira_meta.json.gz",
+                                raw,
+                            )));
+                        }
+
+                        if options.ugoira_sidecars {
+                            contents.push(UnsyncContent::File(UnsyncFileMeta::new(
+                                "ugoira.zip".to_string(),
+                                "application/zip".to_string(),
+                                ArchiveRequest::Original(ugoira.original_src.clone()),
+                            )));
+                            contents.push(UnsyncContent::File(json_sidecar(
+                                "frames.json",
+                                &json!(ugoira.frames),
+                            )));
+                        }
+
                         contents.push(UnsyncContent::File(
                             UnsyncFileMeta::new(
-                                "ugoira.webm".to_string(),
-                                "video/webm".to_string(),
+                                format!("ugoira.{}", options.ugoira_format.extension()),
+                                options.ugoira_format.mime().to_string(),
                                 ArchiveRequest::Ugoira {
                                     url: ugoira.original_src,
                                     frames: ugoira.frames,
+                                    format: options.ugoira_format,
                                 },
                             )
                             .extra(extra),
@@ -515,10 +1835,14 @@ mod common {
                 }
             }
             PixivArtworkContent::Novel {
-                content, cover_url, ..
+                content,
+                cover_url,
+                text_embedded_images,
             } => {
-                contents.push(UnsyncContent::Text(content.clone()));
-                thumb = Some(novel::parse_cover(cover_url));
+                contents.extend(
+                    novel::parse_content(client, content, text_embedded_images.as_ref()).await,
+                );
+                thumb = Some(novel::resolve_cover(client, cover_url).await);
             }
         };
 
@@ -533,31 +1857,230 @@ mod illust {
 
     pub async fn fetch_pages(
         client: &PixivClient,
-        artwork_id: &str,
+        artwork: &PixivArtwork,
+        options: &ArtworkOptions,
     ) -> Result<Vec<UnsyncFileMeta<ArchiveRequest>>> {
         let pages = client
             .fetch::<Vec<PixivIllustPages>>(&format!(
                 "https://www.pixiv.net/ajax/illust/{}/pages?lang=ja",
-                &artwork_id
+                &artwork.id
             ))
             .await?;
 
-        Ok(pages
-            .into_iter()
-            .map(|page| {
-                url_into_file_meta(page.urls.original, None, None).extra(HashMap::from([
+        let mut file_metas = vec![];
+        // the first page is always kept, as the thumb
+        let max_pages = options.max_pages.unwrap_or(usize::MAX).max(1);
+        for (index, page) in pages.into_iter().enumerate().take(max_pages) {
+            let original = page.urls.original.clone();
+            let url = match (options.quality, options.max_original_bytes) {
+                (Quality::Original, Some(max)) => select_url(client, page.urls, max).await,
+                (Quality::Original, None) => page.urls.original,
+                (Quality::Regular, _) => page.urls.regular,
+                (Quality::Small, _) => page.urls.small,
+            };
+
+            let name = url.rsplit('/').next().unwrap_or_default();
+            let filename = options.page_filename(artwork, index, name);
+            let is_original = url == original;
+            let file_meta = url_into_file_meta(url, Some(filename), None);
+            // the reported size is the one of the original
+            file_metas.push(match is_original {
+                true => file_meta.extra(HashMap::from([
                     ("width".to_string(), json!(page.width)),
                     ("height".to_string(), json!(page.height)),
-                ]))
+                ])),
+                false => file_meta,
+            });
+        }
+        Ok(file_metas)
+    }
+
+    /// Original url, or the regular one when the original is larger than `max` bytes
+    async fn select_url(client: &PixivClient, urls: PixivIllustPageUrls, max: u64) -> String {
+        match client.content_length(&urls.original).await {
+            Ok(Some(size)) if size > max => {
+                info!(
+                    "[artwork] Original {} is {size} bytes (> {max}), using {}",
+                    urls.original, urls.regular
+                );
+                urls.regular
+            }
+            Ok(_) => urls.original,
+            Err(e) => {
+                warn!("[artwork] Failed to check size of {}: {e}", urls.original);
+                urls.original
+            }
+        }
+    }
+
+    /// Pages already archived in the post folder, only if every page is present
+    pub fn existing_pages(
+        dir: &Path,
+        artwork: &PixivArtwork,
+        options: &ArtworkOptions,
+    ) -> Option<Vec<UnsyncFileMeta<ArchiveRequest>>> {
+        if artwork.page_count == 0 {
+            return None;
+        }
+
+        let entries = std::fs::read_dir(dir)
+            .ok()?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect::<Vec<_>>();
+
+        (0..artwork.page_count)
+            .map(|page| {
+                let prefix = format!("{}_p{page}.", artwork.id);
+                let path = entries.iter().find(|path| {
+                    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                        return false;
+                    };
+                    match options.filename_template {
+                        // the original name only matters for `{name}`, which the template may not use
+                        Some(_) => {
+                            let ext = name.rsplit_once('.').map_or("", |(_, ext)| ext);
+                            let original = format!("{}_p{page}.{ext}", artwork.id);
+                            options.page_filename(artwork, page as usize, &original) == name
+                        }
+                        None => name.starts_with(&prefix),
+                    }
+                })?;
+
+                if path.metadata().ok()?.len() == 0 {
+                    return None;
+                }
+
+                let filename = path.file_name()?.to_str()?.to_string();
+                let (width, height) = image::image_dimensions(path).ok()?;
+                let mime = mime_guess::from_path(&filename).first_or_octet_stream();
+                Some(
+                    UnsyncFileMeta::new(
+                        filename,
+                        mime.to_string(),
+                        ArchiveRequest::Existing(path.display().to_string()),
+                    )
+                    .extra(HashMap::from([
+                        ("width".to_string(), json!(width)),
+                        ("height".to_string(), json!(height)),
+                    ])),
+                )
             })
-            .collect())
+            .collect()
     }
 }
 
 mod novel {
     use super::*;
 
-    pub fn parse_cover(url: &str) -> UnsyncFileMeta<ArchiveRequest> {
+    /// Split the text at `[uploadedimage:]` and `[pixivimage:]` markers, putting the images in their place
+    pub async fn parse_content(
+        client: &PixivClient,
+        content: &str,
+        embedded: Option<&HashMap<String, PixivNovelImage>>,
+    ) -> Vec<UnsyncContent<ArchiveRequest>> {
+        let content = crate::novel_format::to_markdown(content);
+        let mut contents = vec![];
+        let mut text = String::new();
+        let mut rest = content.as_str();
+
+        while let Some(start) = rest.find('[') {
+            let (before, after) = rest.split_at(start);
+            text.push_str(before);
+
+            let image = match after.find(']') {
+                Some(end) => {
+                    let url = match after[1..end].split_once(':') {
+                        Some(("uploadedimage", id)) => embedded
+                            .and_then(|images| images.get(id))
+                            .map(|image| image.urls.original.clone()),
+                        Some(("pixivimage", id)) => embedded_illust(client, id).await,
+                        _ => None,
+                    };
+                    url.map(|url| (url, end))
+                }
+                None => None,
+            };
+
+            match image {
+                Some((url, end)) => {
+                    if !text.trim().is_empty() {
+                        contents.push(UnsyncContent::Text(std::mem::take(&mut text)));
+                    }
+                    text.clear();
+                    contents.push(UnsyncContent::File(url_into_file_meta(url, None, None)));
+                    rest = &after[end + 1..];
+                }
+                // not an image marker, keep it as text
+                None => {
+                    text.push('[');
+                    rest = &after[1..];
+                }
+            }
+        }
+
+        text.push_str(rest);
+        if !text.trim().is_empty() {
+            contents.push(UnsyncContent::Text(text));
+        }
+        contents
+    }
+
+    /// Original url of an illust embedded as `[pixivimage:id]` or `[pixivimage:id-page]`
+    async fn embedded_illust(client: &PixivClient, marker: &str) -> Option<String> {
+        let (id, page) = match marker.split_once('-') {
+            Some((id, page)) => (id, page.parse::<usize>().ok()?),
+            None => (marker, 1),
+        };
+
+        match client
+            .fetch::<Vec<PixivIllustPages>>(&format!(
+                "https://www.pixiv.net/ajax/illust/{id}/pages?lang=ja"
+            ))
+            .await
+        {
+            Ok(pages) => pages
+                .into_iter()
+                .nth(page.checked_sub(1)?)
+                .map(|page| page.urls.original),
+            Err(e) => {
+                warn!("[artwork] Failed to fetch embedded illust {marker}: {e}");
+                None
+            }
+        }
+    }
+
+    /// Original resolution cover, falling back to the resized one from the novel body
+    pub async fn resolve_cover(client: &PixivClient, url: &str) -> UnsyncFileMeta<ArchiveRequest> {
+        for (original, ext) in original_cover_candidates(url).unwrap_or_default() {
+            match client.content_length(&original).await {
+                Ok(_) => {
+                    return url_into_file_meta(original, Some(format!("cover.{ext}")), None);
+                }
+                Err(e) => trace!("[artwork] No original cover at {original}: {e}"),
+            }
+        }
+
+        warn!("[artwork] Original cover of {url} not found, using the resized one");
+        parse_cover(url)
+    }
+
+    /// `https://i.pximg.net/c/600x600/novel-cover-master/img/.../ci1_hash_master1200.jpg`
+    /// into `https://i.pximg.net/novel-cover-original/img/.../ci1_hash.{ext}`
+    fn original_cover_candidates(url: &str) -> Option<Vec<(String, &'static str)>> {
+        let (host, path) = url.split_once("/novel-cover-master/")?;
+        let host = host.split("/c/").next()?;
+        let (stem, _) = path.rsplit_once('.')?;
+        let stem = stem.strip_suffix("_master1200").unwrap_or(stem);
+
+        Some(
+            ["jpg", "png", "gif"]
+                .into_iter()
+                .map(|ext| (format!("{host}/novel-cover-original/{stem}.{ext}"), ext))
+                .collect(),
+        )
+    }
+
+    fn parse_cover(url: &str) -> UnsyncFileMeta<ArchiveRequest> {
         url_into_file_meta(
             url.to_string(),
             Some("cover.jpg".to_string()),
@@ -595,3 +2118,38 @@ fn url_into_file_meta(
         },
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamp_dates() {
+        let published = common::parse_date("2024-01-31T12:00:00+09:00");
+        let earlier = common::parse_date("2024-01-30T12:00:00+09:00");
+        let later = common::parse_date("2024-02-01T12:00:00+09:00");
+
+        assert_eq!(common::clamp_dates(published, later), (published, later));
+        assert_eq!(common::clamp_dates(published, published), (published, published));
+        // an upload before the creation is clamped to it
+        assert_eq!(common::clamp_dates(published, earlier), (published, published));
+    }
+
+    #[test]
+    fn ai_tags() {
+        let platform = PlatformId::from(0u32);
+        let names = |tags: &[UnsyncTag]| tags.iter().map(|tag| tag.name.clone()).collect::<Vec<_>>();
+
+        assert!(common::ai_tags(AiType::No, true, platform).is_empty());
+        assert!(common::ai_tags(AiType::Unknown, true, platform).is_empty());
+
+        let tags = common::ai_tags(AiType::Yes, false, platform);
+        assert_eq!(names(&tags), ["AI生成"]);
+        assert!(tags[0].platform.is_some());
+
+        let tags = common::ai_tags(AiType::Yes, true, platform);
+        assert_eq!(names(&tags), ["AI生成", "ai-generated"]);
+        // a tag of the archive, not of pixiv
+        assert!(tags[1].platform.is_none());
+    }
+}
@@ -0,0 +1,121 @@
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::config::{PixivTarget, parse_target};
+
+/// Works, users and tags never archived, read from the file of `--blocklist`.
+///
+/// One entry per line, `#` starts a comment:
+/// - `user:123` or a user url
+/// - `work:456` or an artwork url
+/// - `tag:R-18*` as a glob, or `tag:/^AI/` as a regex
+#[derive(Debug, Clone, Default)]
+pub struct Blocklist {
+    pub works: Vec<u64>,
+    pub users: Vec<u64>,
+    pub tags: Vec<Regex>,
+}
+
+impl Blocklist {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+        let mut blocklist = Self::default();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            blocklist
+                .add(line)
+                .map_err(|e| format!("line {}: {e}", number + 1))?;
+        }
+        Ok(blocklist)
+    }
+
+    fn add(&mut self, entry: &str) -> Result<(), String> {
+        let id = |value: &str| {
+            value
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| format!("invalid id `{value}`"))
+        };
+
+        if let Some(user) = entry.strip_prefix("user:") {
+            self.users.push(id(user)?);
+        } else if let Some(work) = entry.strip_prefix("work:") {
+            self.works.push(id(work)?);
+        } else if let Some(tag) = entry.strip_prefix("tag:") {
+            self.tags.push(tag_pattern(tag.trim())?);
+        } else {
+            match parse_target(entry)? {
+                PixivTarget::User(user) => self.users.push(user),
+                PixivTarget::Artwork(artwork) => match artwork.id() {
+                    Some(id) => self.works.push(id),
+                    None => return Err(format!("unlisted works can't be blocked: `{entry}`")),
+                },
+                _ => return Err(format!("expected `user:`, `work:` or `tag:`, got `{entry}`")),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `/regex/`, or a glob matching the whole tag with `*` and `?`
+fn tag_pattern(pattern: &str) -> Result<Regex, String> {
+    if let Some(regex) = pattern
+        .strip_prefix('/')
+        .and_then(|pattern| pattern.strip_suffix('/'))
+    {
+        return Regex::new(regex).map_err(|e| e.to_string());
+    }
+
+    let glob = regex::escape(pattern)
+        .replace(r"\*", ".*")
+        .replace(r"\?", ".");
+    Regex::new(&format!("^{glob}$")).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_entries() {
+        let mut blocklist = Blocklist::default();
+        blocklist.add("user:12").unwrap();
+        blocklist.add("work: 34").unwrap();
+        blocklist.add("https://www.pixiv.net/users/56").unwrap();
+        blocklist.add("https://www.pixiv.net/artworks/78").unwrap();
+        blocklist.add("tag:R-18*").unwrap();
+        assert_eq!(blocklist.users, [12, 56]);
+        assert_eq!(blocklist.works, [34, 78]);
+        assert_eq!(blocklist.tags.len(), 1);
+
+        assert!(blocklist.add("user:abc").is_err());
+        assert!(blocklist.add("https://www.pixiv.net/artworks/unlisted/abc").is_err());
+        assert!(blocklist.add("https://www.pixiv.net/novel/series/1").is_err());
+        assert!(blocklist.add("tag:/(/").is_err());
+    }
+
+    #[test]
+    fn tag_patterns() {
+        let glob = tag_pattern("R-18*").unwrap();
+        assert!(glob.is_match("R-18"));
+        assert!(glob.is_match("R-18G"));
+        assert!(!glob.is_match("not R-18"));
+
+        let single = tag_pattern("a?c").unwrap();
+        assert!(single.is_match("abc"));
+        assert!(!single.is_match("abbc"));
+
+        // regex characters of a glob are literal
+        assert!(tag_pattern("1+1").unwrap().is_match("1+1"));
+        assert!(!tag_pattern("1+1").unwrap().is_match("11"));
+
+        let regex = tag_pattern("/^AI/").unwrap();
+        assert!(regex.is_match("AI生成"));
+        assert!(!regex.is_match("HAIKU"));
+    }
+}
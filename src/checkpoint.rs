@@ -0,0 +1,98 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Progress of the run, saved as `checkpoint.json` so an interrupted run can continue with `--resume`
+#[derive(Debug, Clone, Default)]
+pub struct Checkpoint {
+    inner: Arc<Mutex<CheckpointState>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct CheckpointState {
+    #[serde(skip)]
+    path: PathBuf,
+    /// urls of the works not archived yet, as accepted on the command line
+    pending: BTreeSet<String>,
+    /// position of the listings, e.g. `favorite:illusts:` to its offset
+    cursors: BTreeMap<String, usize>,
+}
+
+impl Checkpoint {
+    pub fn new(root: &Path) -> Self {
+        let state = CheckpointState {
+            path: root.join("checkpoint.json"),
+            ..Default::default()
+        };
+        Self {
+            inner: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Checkpoint saved by a previous run, empty if there is none
+    pub fn load(root: &Path) -> io::Result<Self> {
+        let path = root.join("checkpoint.json");
+        let mut state = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice::<CheckpointState>(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => CheckpointState::default(),
+            Err(e) => return Err(e),
+        };
+        state.path = path;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.inner.lock().unwrap().path.clone()
+    }
+
+    pub fn pending(&self) -> Vec<String> {
+        self.inner.lock().unwrap().pending.iter().cloned().collect()
+    }
+
+    pub fn contains(&self, url: &str) -> bool {
+        self.inner.lock().unwrap().pending.contains(url)
+    }
+
+    pub fn add(&self, url: String) {
+        self.inner.lock().unwrap().pending.insert(url);
+    }
+
+    pub fn remove(&self, url: &str) {
+        self.inner.lock().unwrap().pending.remove(url);
+    }
+
+    pub fn cursor(&self, key: &str) -> Option<usize> {
+        self.inner.lock().unwrap().cursors.get(key).copied()
+    }
+
+    pub fn set_cursor(&self, key: &str, value: usize) {
+        let mut state = self.inner.lock().unwrap();
+        state.cursors.insert(key.to_string(), value);
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let state = self.inner.lock().unwrap();
+        if state.path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        fs::write(&state.path, serde_json::to_vec_pretty(&*state)?)
+    }
+
+    /// Forget everything, after a run which finished
+    pub fn clear(&self) -> io::Result<()> {
+        let mut state = self.inner.lock().unwrap();
+        state.pending.clear();
+        state.cursors.clear();
+        match fs::remove_file(&state.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
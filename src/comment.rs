@@ -1,9 +1,110 @@
+use std::{collections::BTreeSet, sync::Mutex};
+
 use futures::future::join_all;
 use log::error;
 use post_archiver::Comment;
 use serde::Deserialize;
 
-use crate::api::PixivClient;
+use crate::{
+    api::PixivClient,
+    config::{AvatarSize, CommentEmoji},
+    file::ArchiveRequest,
+};
+
+/// Emoji slugs of pixiv comments, with the id of their sprite and a similar unicode emoji
+const EMOJI: &[(&str, u32, &str)] = &[
+    ("normal", 101, "🙂"),
+    ("surprise", 102, "😮"),
+    ("serious", 103, "😐"),
+    ("heaven", 104, "😇"),
+    ("happy", 105, "😄"),
+    ("excited", 106, "🤩"),
+    ("sing", 107, "🎶"),
+    ("cry", 108, "😢"),
+    ("normal2", 201, "🙂"),
+    ("shame2", 202, "😳"),
+    ("love2", 203, "😍"),
+    ("interesting2", 204, "😆"),
+    ("blush2", 205, "☺️"),
+    ("fire2", 206, "🔥"),
+    ("angry2", 207, "😠"),
+    ("shine2", 208, "✨"),
+    ("panic2", 209, "😱"),
+    ("normal3", 301, "🙂"),
+    ("satisfaction3", 302, "😌"),
+    ("surprise3", 303, "😲"),
+    ("smile3", 304, "😀"),
+    ("shock3", 305, "😨"),
+    ("gaze3", 306, "👀"),
+    ("wink3", 307, "😉"),
+    ("happy3", 308, "😁"),
+    ("excited3", 309, "😆"),
+    ("love3", 310, "🥰"),
+    ("normal4", 401, "🙂"),
+    ("surprise4", 402, "😯"),
+    ("serious4", 403, "😑"),
+    ("love4", 404, "😘"),
+    ("shine4", 405, "🌟"),
+    ("sweat4", 406, "😅"),
+    ("shame4", 407, "😖"),
+    ("sleep4", 408, "😪"),
+    ("heart", 501, "❤️"),
+    ("teardrop", 502, "💧"),
+    ("star", 503, "⭐"),
+];
+
+/// Image used in comments, which can be attached to the post
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CommentImage {
+    Stamp(String),
+    Emoji(&'static str, u32),
+    Avatar {
+        user_id: String,
+        user_name: String,
+        url: String,
+        /// avatar as provided by the comment api
+        fallback: String,
+    },
+}
+
+impl CommentImage {
+    pub fn filename(&self) -> String {
+        match self {
+            CommentImage::Stamp(id) => format!("stamp_{id}.jpg"),
+            CommentImage::Emoji(name, _) => format!("emoji_{name}.png"),
+            CommentImage::Avatar { user_id, url, .. } => {
+                let ext = url.rsplit_once('.').map(|(_, ext)| ext).unwrap_or("jpg");
+                format!("avatar_{user_id}.{ext}")
+            }
+        }
+    }
+
+    pub fn request(&self) -> ArchiveRequest {
+        match self {
+            CommentImage::Stamp(id) => ArchiveRequest::Image(format!(
+                "https://s.pximg.net/common/images/stamp/generated-stamps/{id}_s.jpg"
+            )),
+            CommentImage::Emoji(_, id) => ArchiveRequest::Image(format!(
+                "https://s.pximg.net/common/images/emoji/{id}.png"
+            )),
+            CommentImage::Avatar { url, fallback, .. } if url != fallback => {
+                ArchiveRequest::ImageWithFallback {
+                    url: url.clone(),
+                    fallback: fallback.clone(),
+                }
+            }
+            CommentImage::Avatar { url, .. } => ArchiveRequest::Image(url.clone()),
+        }
+    }
+}
+
+/// Options of the comment crawl
+#[derive(Debug, Clone, Copy)]
+pub struct CommentOptions {
+    pub max_comments: Option<usize>,
+    pub emoji: CommentEmoji,
+    pub avatar_size: AvatarSize,
+}
 
 #[derive(Debug, Clone, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
@@ -29,49 +130,163 @@ pub struct PixivComment {
     pub stamp_id: Option<String>,
 }
 
+/// Rewrite an avatar url (e.g. `.../12345_abcdef_50.jpg`) to the requested size
+pub fn avatar_url(img: &str, size: AvatarSize) -> String {
+    let Some((stem, ext)) = img.rsplit_once('.') else {
+        return img.to_string();
+    };
+
+    let stem = match stem.rsplit_once('_') {
+        Some((base, suffix)) if suffix.chars().all(|c| c.is_ascii_digit()) => base,
+        _ => stem,
+    };
+
+    match size {
+        AvatarSize::Small => format!("{stem}_50.{ext}"),
+        AvatarSize::Medium => format!("{stem}_170.{ext}"),
+        AvatarSize::Original => format!("{stem}.{ext}"),
+    }
+}
+
+/// Comments of the artwork, with the stamps and emoji used in them
 pub async fn get_comments(
     client: &PixivClient,
     id: &str,
     is_novel: bool,
-    is_root: bool,
-) -> Vec<Comment> {
+    options: CommentOptions,
+) -> (Vec<Comment>, BTreeSet<CommentImage>) {
+    const LIMIT: usize = 50;
+
     let ty = if is_novel { "novel" } else { "illust" };
-    let url = match is_root {
-        true => {
-            format!("https://www.pixiv.net/ajax/{ty}s/comments/roots?{ty}_id={id}&limit=4294967295")
-        }
-        false => {
-            format!("https://www.pixiv.net/ajax/{ty}s/comments/replies?comment_id={id}&page=1")
+    let max_comments = options.max_comments.unwrap_or(usize::MAX);
+
+    let mut roots = vec![];
+    while roots.len() < max_comments {
+        let offset = roots.len();
+        let url = format!(
+            "https://www.pixiv.net/ajax/{ty}s/comments/roots?{ty}_id={id}&offset={offset}&limit={LIMIT}"
+        );
+
+        let page = match client.fetch::<PixivComments>(&url).await {
+            Ok(page) => page,
+            Err(e) => {
+                error!("[artwork][comment] Failed fetch {ty} {id} comments: {e:?}");
+                break;
+            }
+        };
+
+        let has_next = page.has_next && !page.comments.is_empty();
+        roots.extend(page.comments);
+        if !has_next {
+            break;
         }
+    }
+    roots.truncate(max_comments);
+
+    let context = CommentContext {
+        is_novel,
+        emoji: options.emoji,
+        avatar_size: options.avatar_size,
+        images: Mutex::new(BTreeSet::new()),
     };
+    let comments = join_all(
+        roots
+            .into_iter()
+            .map(async |comment| into_comment(client, comment, &context).await),
+    )
+    .await;
+    (comments, context.images.into_inner().unwrap())
+}
+
+struct CommentContext {
+    is_novel: bool,
+    emoji: CommentEmoji,
+    avatar_size: AvatarSize,
+    images: Mutex<BTreeSet<CommentImage>>,
+}
+
+async fn get_replies(client: &PixivClient, id: &str, context: &CommentContext) -> Vec<Comment> {
+    let ty = if context.is_novel { "novel" } else { "illust" };
 
-    let PixivComments { comments, .. } = client.fetch(&url)
-        .await
-        .inspect_err(|e| {
-            let cty = if is_root { "comments" } else { "replies" };
-            error!("[artwork][comment] Failed fetch {ty} {id} {cty}: {e:?}");
-        })
-        .unwrap_or_default();
-
-    join_all(comments.into_iter().map(async |comment| {
-        let replies = if comment.has_replies {
-            get_comments(client, &comment.id, is_novel, false).await
-        } else {
-            vec![]
+    let mut replies = vec![];
+    let mut page = 1;
+    loop {
+        let url = format!(
+            "https://www.pixiv.net/ajax/{ty}s/comments/replies?comment_id={id}&page={page}"
+        );
+
+        let PixivComments { has_next, comments } = match client.fetch(&url).await {
+            Ok(comments) => comments,
+            Err(e) => {
+                error!("[artwork][comment] Failed fetch {ty} {id} replies (page {page}): {e:?}");
+                break;
+            }
         };
 
-        Comment {
-            user: comment.user_name,
-            text: [
-                comment.content,
-                comment
-                    .stamp_id
-                    .map(|id| format!("(Stamp {id})"))
-                    .unwrap_or_default(),
-            ]
-            .join(" "),
-            replies,
+        let has_next = has_next && !comments.is_empty();
+        replies.extend(comments);
+        if !has_next {
+            break;
         }
-    }))
+        page += 1;
+    }
+
+    join_all(
+        replies
+            .into_iter()
+            .map(async |comment| into_comment(client, comment, context).await),
+    )
     .await
 }
+
+async fn into_comment(
+    client: &PixivClient,
+    mut comment: PixivComment,
+    context: &CommentContext,
+) -> Comment {
+    let replies = if comment.has_replies {
+        Box::pin(get_replies(client, &comment.id, context)).await
+    } else {
+        vec![]
+    };
+
+    let mut images = context.images.lock().unwrap();
+    images.insert(CommentImage::Avatar {
+        user_id: comment.user_id.clone(),
+        user_name: comment.user_name.clone(),
+        url: avatar_url(&comment.img, context.avatar_size),
+        fallback: comment.img.clone(),
+    });
+    if let Some(id) = &comment.stamp_id {
+        images.insert(CommentImage::Stamp(id.clone()));
+    }
+
+    for &(name, id, unicode) in EMOJI {
+        let slug = format!("({name})");
+        if !comment.content.contains(&slug) {
+            continue;
+        }
+
+        match context.emoji {
+            CommentEmoji::Keep => {}
+            CommentEmoji::Unicode => comment.content = comment.content.replace(&slug, unicode),
+            CommentEmoji::Image => {
+                images.insert(CommentImage::Emoji(name, id));
+            }
+        }
+    }
+    drop(images);
+
+    Comment {
+        user: comment.user_name,
+        text: [
+            comment.content,
+            comment
+                .stamp_id
+                .map(|id| format!("(Stamp {id})"))
+                .unwrap_or_default(),
+        ]
+        .join(" "),
+        replies,
+    }
+}
@@ -0,0 +1,160 @@
+use std::path::{Path, PathBuf};
+
+use aes::{
+    Aes128,
+    cipher::{BlockDecrypt, KeyInit, generic_array::GenericArray},
+};
+use clap::ValueEnum;
+use rusqlite::{Connection, OpenFlags};
+use sha1::Sha1;
+
+/// Browser to read the pixiv session from
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq)]
+pub enum Browser {
+    Firefox,
+    Chrome,
+    Edge,
+}
+
+/// `PHPSESSID` of pixiv, read from the cookie store of the browser
+pub fn load_session(browser: Browser) -> Result<String, String> {
+    // only the fixed password of Linux is known, macOS and Windows keep the key in the system
+    if browser != Browser::Firefox && !cfg!(target_os = "linux") {
+        return Err(format!(
+            "the cookies of {browser:?} are encrypted by the system keychain, which is only read on Linux, use Firefox or copy `PHPSESSID` manually"
+        ));
+    }
+
+    let stores = cookie_stores(browser);
+    let store = stores
+        .iter()
+        .find(|store| store.exists())
+        .ok_or_else(|| format!("no cookie store of {browser:?} found"))?;
+
+    // the store is locked while the browser runs, so read a copy
+    let copy = tempfile::NamedTempFile::new().map_err(|e| e.to_string())?;
+    std::fs::copy(store, copy.path()).map_err(|e| format!("{}: {e}", store.display()))?;
+    let conn = Connection::open_with_flags(copy.path(), OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| e.to_string())?;
+
+    let session = match browser {
+        Browser::Firefox => conn.query_row(
+            "SELECT value FROM moz_cookies WHERE host LIKE '%pixiv.net' AND name = 'PHPSESSID' ORDER BY expiry DESC LIMIT 1",
+            [],
+            |row| row.get::<_, String>(0),
+        ),
+        Browser::Chrome | Browser::Edge => conn
+            .query_row(
+                "SELECT value, encrypted_value FROM cookies WHERE host_key LIKE '%pixiv.net' AND name = 'PHPSESSID' ORDER BY expires_utc DESC LIMIT 1",
+                [],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)),
+            )
+            .map(|(value, encrypted)| match value.is_empty() {
+                true => decrypt_chromium(&encrypted).unwrap_or_default(),
+                false => value,
+            }),
+    };
+
+    match session {
+        Ok(session) if !session.is_empty() => Ok(session),
+        Ok(_) => Err(format!("failed to decrypt the pixiv session of {browser:?}")),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            Err(format!("not logged in to pixiv in {browser:?}"))
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn cookie_stores(browser: Browser) -> Vec<PathBuf> {
+    let home = std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    let local = std::env::var_os("LOCALAPPDATA")
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    let roaming = std::env::var_os("APPDATA")
+        .map(PathBuf::from)
+        .unwrap_or_default();
+
+    match browser {
+        Browser::Firefox => [
+            home.join(".mozilla/firefox"),
+            home.join("snap/firefox/common/.mozilla/firefox"),
+            home.join("Library/Application Support/Firefox/Profiles"),
+            roaming.join("Mozilla/Firefox/Profiles"),
+        ]
+        .iter()
+        .flat_map(|dir| firefox_profiles(dir))
+        .collect(),
+        Browser::Chrome | Browser::Edge => {
+            let (linux, macos, windows) = match browser {
+                Browser::Chrome => ("google-chrome", "Google/Chrome", "Google/Chrome/User Data"),
+                _ => ("microsoft-edge", "Microsoft Edge", "Microsoft/Edge/User Data"),
+            };
+            [
+                home.join(".config").join(linux),
+                home.join("Library/Application Support").join(macos),
+                local.join(windows),
+            ]
+            .iter()
+            .flat_map(|dir| [dir.join("Default/Network/Cookies"), dir.join("Default/Cookies")])
+            .collect()
+        }
+    }
+}
+
+/// Cookie stores of the Firefox profiles, the most recently used first
+fn firefox_profiles(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+
+    let mut stores = entries
+        .flatten()
+        .map(|entry| entry.path().join("cookies.sqlite"))
+        .filter_map(|store| {
+            let modified = store.metadata().and_then(|meta| meta.modified()).ok()?;
+            Some((modified, store))
+        })
+        .collect::<Vec<_>>();
+    stores.sort_by(|a, b| b.0.cmp(&a.0));
+    stores.into_iter().map(|(_, store)| store).collect()
+}
+
+/// Decrypt a `v10` cookie of Chromium on Linux, stored with the fixed `peanuts` password
+/// when no keyring is available
+fn decrypt_chromium(encrypted: &[u8]) -> Option<String> {
+    let data = encrypted.strip_prefix(b"v10")?;
+    if data.is_empty() || data.len() % 16 != 0 {
+        return None;
+    }
+
+    let mut key = [0u8; 16];
+    pbkdf2::pbkdf2_hmac::<Sha1>(b"peanuts", b"saltysalt", 1, &mut key);
+    let cipher = Aes128::new(GenericArray::from_slice(&key));
+
+    // aes-128-cbc with an iv of spaces
+    let mut previous = [b' '; 16];
+    let mut plain = Vec::with_capacity(data.len());
+    for chunk in data.chunks(16) {
+        let mut block = GenericArray::clone_from_slice(chunk);
+        cipher.decrypt_block(&mut block);
+        plain.extend(block.iter().zip(previous).map(|(byte, iv)| byte ^ iv));
+        previous.copy_from_slice(chunk);
+    }
+
+    // pkcs7 padding
+    let padding = *plain.last()? as usize;
+    if padding == 0 || padding > 16 {
+        return None;
+    }
+    plain.truncate(plain.len() - padding);
+
+    // newer versions prefix the value with the sha256 of the domain
+    let value = match plain.len() > 32 && std::str::from_utf8(&plain).is_err() {
+        true => &plain[32..],
+        false => &plain[..],
+    };
+    String::from_utf8(value.to_vec()).ok()
+}
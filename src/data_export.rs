@@ -0,0 +1,203 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::Read,
+    path::Path,
+    sync::LazyLock,
+};
+
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{
+    artwork::PixivArtworkId,
+    config::{PixivTarget, parse_target},
+};
+
+/// Files of the export which may list works, the images it bundles are skipped
+const TEXT_EXTENSIONS: [&str; 7] = ["json", "csv", "tsv", "txt", "html", "htm", "xml"];
+
+static PIXIV_URL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?:https?://)?(?:www\.)?pixiv\.net/[^\s"'<>,;)\]]+"#).unwrap()
+});
+
+/// Works and users of a pixiv personal data export, the zip or its extracted folder.
+///
+/// The bookmark, work and following lists (csv, tsv or json) are read by their id columns,
+/// e.g. `illust_id` or a plain `id` in `bookmark_novels.csv`. The other text files, and the
+/// lists without a known column, are searched for pixiv urls instead.
+pub fn load(path: &Path) -> Result<Vec<PixivTarget>, String> {
+    let mut files = vec![];
+    match path.is_dir() {
+        true => read_dir(path, &mut files).map_err(|e| e.to_string())?,
+        false if has_extension(path, &["zip"]) => read_zip(path, &mut files)?,
+        false => files.push((
+            path.display().to_string(),
+            std::fs::read(path).map_err(|e| e.to_string())?,
+        )),
+    }
+
+    let mut seen = HashSet::new();
+    let mut targets = vec![];
+    for (path, content) in &files {
+        // only the file name tells the list, not the folders of the export
+        let name = Path::new(path)
+            .file_name()
+            .map_or(path.to_lowercase(), |name| name.to_string_lossy().to_lowercase());
+        let text = String::from_utf8_lossy(content);
+        let mut found = vec![];
+        if has_extension(Path::new(&name), &["csv", "tsv"]) {
+            read_table(&text, &name, &mut found);
+        } else if has_extension(Path::new(&name), &["json"])
+            && let Ok(value) = serde_json::from_str::<Value>(&text)
+        {
+            read_json(&value, &name, &mut found);
+        }
+        if found.is_empty() {
+            read_urls(&text, &mut found);
+        }
+
+        for target in found {
+            if seen.insert(target.clone()) {
+                targets.push(target);
+            }
+        }
+    }
+    Ok(targets)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum IdKind {
+    Illust,
+    Novel,
+    User,
+}
+
+impl IdKind {
+    /// Kind of the ids of a column (or json key) of the file, `None` for other columns.
+    /// The author ids of bookmarked works are skipped, only the following list adds users.
+    fn of(column: &str, file: &str) -> Option<Self> {
+        let column = column.to_lowercase().replace(['_', '-', ' '], "");
+        match column.as_str() {
+            "illustid" | "artworkid" => Some(IdKind::Illust),
+            "novelid" => Some(IdKind::Novel),
+            "userid" | "followuserid" | "targetuserid" if file.contains("follow") => {
+                Some(IdKind::User)
+            }
+            "id" | "workid" => match file {
+                _ if file.contains("novel") => Some(IdKind::Novel),
+                _ if file.contains("follow") => Some(IdKind::User),
+                _ if ["bookmark", "illust", "manga", "work"]
+                    .iter()
+                    .any(|list| file.contains(list)) =>
+                {
+                    Some(IdKind::Illust)
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn target(self, id: &str) -> Option<PixivTarget> {
+        let id = id.trim().parse::<u64>().ok().filter(|id| *id > 0)?;
+        Some(match self {
+            IdKind::Illust => PixivTarget::Artwork(PixivArtworkId::Illust(id)),
+            IdKind::Novel => PixivTarget::Artwork(PixivArtworkId::Novel(id)),
+            IdKind::User => PixivTarget::User(id),
+        })
+    }
+}
+
+fn read_table(text: &str, file: &str, targets: &mut Vec<PixivTarget>) {
+    let delimiter = match has_extension(Path::new(file), &["tsv"]) {
+        true => b'\t',
+        false => b',',
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+    let Ok(headers) = reader.headers() else {
+        return;
+    };
+    let columns = headers
+        .iter()
+        .enumerate()
+        .filter_map(|(index, column)| Some((index, IdKind::of(column, file)?)))
+        .collect::<Vec<_>>();
+    if columns.is_empty() {
+        return;
+    }
+
+    for record in reader.records().flatten() {
+        for (index, kind) in &columns {
+            if let Some(target) = record.get(*index).and_then(|id| kind.target(id)) {
+                targets.push(target);
+            }
+        }
+    }
+}
+
+fn read_json(value: &Value, file: &str, targets: &mut Vec<PixivTarget>) {
+    match value {
+        Value::Array(values) => values.iter().for_each(|value| read_json(value, file, targets)),
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                let id = match value {
+                    Value::Number(id) => Some(id.to_string()),
+                    Value::String(id) => Some(id.clone()),
+                    _ => None,
+                };
+                match (IdKind::of(key, file), id) {
+                    (Some(kind), Some(id)) => targets.extend(kind.target(&id)),
+                    _ => read_json(value, file, targets),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn read_urls(text: &str, targets: &mut Vec<PixivTarget>) {
+    for url in PIXIV_URL.find_iter(text) {
+        let url = url.as_str().replace("\\/", "/");
+        if let Ok(target) = parse_target(&url) {
+            targets.push(target);
+        }
+    }
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.contains(&ext.to_lowercase().as_str()))
+}
+
+fn read_dir(dir: &Path, files: &mut Vec<(String, Vec<u8>)>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            read_dir(&path, files)?;
+        } else if has_extension(&path, &TEXT_EXTENSIONS) {
+            files.push((path.display().to_string(), std::fs::read(&path)?));
+        }
+    }
+    Ok(())
+}
+
+fn read_zip(path: &Path, files: &mut Vec<(String, Vec<u8>)>) -> Result<(), String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|e| e.to_string())?;
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index).map_err(|e| e.to_string())?;
+        if file.is_dir() || !has_extension(Path::new(file.name()), &TEXT_EXTENSIONS) {
+            continue;
+        }
+
+        let mut content = vec![];
+        file.read_to_end(&mut content).map_err(|e| e.to_string())?;
+        files.push((file.name().to_string(), content));
+    }
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use chrono::Utc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// Works which Pixiv reported as deleted or restricted, saved as `deleted.json`
+#[derive(Debug, Clone, Default)]
+pub struct DeletedWorks {
+    path: PathBuf,
+    inner: Arc<Mutex<DeletedInner>>,
+}
+
+#[derive(Debug, Default)]
+struct DeletedInner {
+    works: BTreeMap<String, Tombstone>,
+    /// urls recorded by this run
+    recent: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub reason: String,
+    /// RFC 3339 date the work was found missing
+    pub date: String,
+    /// whether a copy was archived before it disappeared, `None` when unknown
+    pub archived: Option<bool>,
+}
+
+impl DeletedWorks {
+    pub fn open(root: &Path) -> Self {
+        let path = root.join("deleted.json");
+        let works = fs::read(&path)
+            .ok()
+            .and_then(|data| {
+                serde_json::from_slice(&data)
+                    .inspect_err(|e| warn!("[deleted] Ignoring invalid {}: {e}", path.display()))
+                    .ok()
+            })
+            .unwrap_or_default();
+
+        Self {
+            path,
+            inner: Arc::new(Mutex::new(DeletedInner {
+                works,
+                recent: vec![],
+            })),
+        }
+    }
+
+    /// Keep the first date a work was found missing, recording it once
+    pub fn record(&self, url: &str, reason: &str, archived: Option<bool>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.works.contains_key(url) {
+            return;
+        }
+
+        let tombstone = Tombstone {
+            reason: reason.to_string(),
+            date: Utc::now().to_rfc3339(),
+            archived,
+        };
+        inner.works.insert(url.to_string(), tombstone);
+        inner.recent.push(url.to_string());
+    }
+
+    /// Forget a work which is available again
+    pub fn remove(&self, url: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.works.remove(url);
+        inner.recent.retain(|recent| recent != url);
+    }
+
+    /// Works found missing since the last call, for the report at the end of each run
+    pub fn take_recent(&self) -> Vec<(String, Tombstone)> {
+        let mut inner = self.inner.lock().unwrap();
+        let recent = std::mem::take(&mut inner.recent);
+        recent
+            .into_iter()
+            .filter_map(|url| Some((url.clone(), inner.works.get(&url)?.clone())))
+            .collect()
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if self.path.as_os_str().is_empty() {
+            return Ok(());
+        }
+
+        let inner = self.inner.lock().unwrap();
+        if inner.works.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        fs::write(&self.path, serde_json::to_vec_pretty(&inner.works)?)
+    }
+}
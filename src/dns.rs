@@ -0,0 +1,108 @@
+use std::net::{IpAddr, SocketAddr};
+
+use reqwest::{
+    Client,
+    dns::{Addrs, Name, Resolve, Resolving},
+    header,
+};
+use serde::Deserialize;
+
+/// `--resolve host:port:ip` pinning the host to the address, as in curl
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolveOverride {
+    pub host: String,
+    pub addr: SocketAddr,
+}
+
+/// Parse `host:port:ip`, e.g. `www.pixiv.net:443:210.140.92.183`
+pub fn parse_resolve(value: &str) -> Result<ResolveOverride, String> {
+    let invalid = || format!("invalid override `{value}`, expected `host:port:ip`");
+    let mut parts = value.splitn(3, ':');
+    let (Some(host), Some(port), Some(ip)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+
+    let port = port.parse::<u16>().map_err(|_| invalid())?;
+    let ip = ip
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map_err(|_| invalid())?;
+    if host.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(ResolveOverride {
+        host: host.to_string(),
+        addr: SocketAddr::new(ip, port),
+    })
+}
+
+/// Resolver asking a DNS-over-HTTPS server by its json api, e.g. `https://1.1.1.1/dns-query`
+#[derive(Debug, Clone)]
+pub struct DohResolver {
+    endpoint: String,
+    client: Client,
+}
+
+#[derive(Debug, Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    ty: u16,
+    data: String,
+}
+
+impl DohResolver {
+    pub fn new(endpoint: &str) -> Self {
+        Self {
+            endpoint: endpoint.to_string(),
+            client: Client::new(),
+        }
+    }
+}
+
+impl Resolve for DohResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        const A: u16 = 1;
+        const AAAA: u16 = 28;
+
+        let resolver = self.clone();
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let mut addrs = vec![];
+            for ty in [A, AAAA] {
+                let bytes = resolver
+                    .client
+                    .get(&resolver.endpoint)
+                    .query(&[("name", host.as_str()), ("type", &ty.to_string())])
+                    .header(header::ACCEPT, "application/dns-json")
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .bytes()
+                    .await?;
+                let response = serde_json::from_slice::<DohResponse>(&bytes)?;
+
+                addrs.extend(
+                    response
+                        .answer
+                        .into_iter()
+                        .filter(|answer| answer.ty == ty)
+                        .filter_map(|answer| answer.data.parse::<IpAddr>().ok())
+                        .map(|ip| SocketAddr::new(ip, 0)),
+                );
+            }
+
+            if addrs.is_empty() {
+                return Err(format!("no address of {host} from {}", resolver.endpoint).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
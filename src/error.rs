@@ -0,0 +1,159 @@
+use post_archiver_utils::Error;
+use reqwest::StatusCode;
+
+pub type PixivResult<T> = std::result::Result<T, PixivError>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PixivError {
+    #[error("Network error: {0}")]
+    Network(String),
+    #[error("HTTP error: {0}")]
+    Http(StatusCode),
+    #[error("Invalid session, please refresh your `PHPSESSID`")]
+    Auth,
+    #[error("Deleted or restricted: {0}")]
+    Deleted(String),
+    #[error("Failed to parse response: {0}")]
+    Parse(String),
+    #[error("Rate limited")]
+    Rate,
+    #[error("Blocked by Pixiv, got a 403 or an html page instead of json")]
+    Blocked,
+    #[error(transparent)]
+    Other(Error),
+}
+
+impl PixivError {
+    /// Classify a failed response by its status and the message Pixiv returned.
+    /// Only a `404` or an explicit deletion message is [`PixivError::Deleted`], a `403` is
+    /// usually a temporary block or a login wall.
+    pub fn from_response(status: StatusCode, message: String) -> Self {
+        match status {
+            StatusCode::TOO_MANY_REQUESTS => PixivError::Rate,
+            StatusCode::UNAUTHORIZED => PixivError::Auth,
+            _ if message.contains("削除") => PixivError::Deleted(message),
+            StatusCode::NOT_FOUND => PixivError::Deleted(message),
+            StatusCode::FORBIDDEN => PixivError::Blocked,
+            status if !status.is_success() => PixivError::Http(status),
+            _ => PixivError::Other(Error::InvalidResponse(message)),
+        }
+    }
+
+    /// Short name of the error, as recorded in `failed.json`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PixivError::Network(_) => "network",
+            PixivError::Http(_) => "http",
+            PixivError::Auth => "auth",
+            PixivError::Deleted(_) => "deleted",
+            PixivError::Parse(_) => "parse",
+            PixivError::Rate => "rate",
+            PixivError::Blocked => "blocked",
+            PixivError::Other(_) => "other",
+        }
+    }
+
+    /// Whether retrying later may succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            PixivError::Network(_) | PixivError::Rate | PixivError::Blocked => true,
+            PixivError::Http(status) => status.is_server_error(),
+            _ => false,
+        }
+    }
+}
+
+impl From<Error> for PixivError {
+    fn from(error: Error) -> Self {
+        let status = match &error {
+            Error::Reqwest(e) => e.status(),
+            Error::ReqwestMiddleware(e) => e.status(),
+            _ => None,
+        };
+
+        match error {
+            Error::InvalidSession => PixivError::Auth,
+            Error::Reqwest(_) | Error::ReqwestMiddleware(_) => match status {
+                Some(status) => PixivError::from_response(status, String::new()),
+                None => PixivError::Network(error.to_string()),
+            },
+            Error::UnexpectedResponse(e, _) => PixivError::Parse(e.to_string()),
+            Error::SerdeJson(e) => PixivError::Parse(e.to_string()),
+            error => PixivError::Other(error),
+        }
+    }
+}
+
+impl From<PixivError> for Error {
+    fn from(error: PixivError) -> Self {
+        match error {
+            PixivError::Auth => Error::InvalidSession,
+            PixivError::Other(error) => error,
+            error => Error::InvalidResponse(error.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind(status: u16, message: &str) -> &'static str {
+        let status = StatusCode::from_u16(status).unwrap();
+        PixivError::from_response(status, message.to_string()).kind()
+    }
+
+    #[test]
+    fn from_response() {
+        assert_eq!(kind(429, ""), "rate");
+        assert_eq!(kind(401, ""), "auth");
+        assert_eq!(kind(404, ""), "deleted");
+        assert_eq!(kind(400, "該当作品は削除されたか、存在しない作品IDです。"), "deleted");
+        // a 403 is a block or a login wall, not a deletion
+        assert_eq!(kind(403, ""), "blocked");
+        assert_eq!(kind(500, ""), "http");
+        assert_eq!(kind(200, "error"), "other");
+    }
+
+    #[test]
+    fn transient() {
+        let status = |status| PixivError::from_response(status, String::new());
+        assert!(status(StatusCode::TOO_MANY_REQUESTS).is_transient());
+        assert!(status(StatusCode::FORBIDDEN).is_transient());
+        assert!(status(StatusCode::BAD_GATEWAY).is_transient());
+        assert!(!status(StatusCode::NOT_FOUND).is_transient());
+        assert!(!status(StatusCode::UNAUTHORIZED).is_transient());
+    }
+
+    #[tokio::test]
+    async fn from_network_error() {
+        // nothing listens on port 1, the connection is refused
+        let error = reqwest::get("http://127.0.0.1:1").await.unwrap_err();
+        let error = PixivError::from(Error::from(error));
+        assert_eq!(error.kind(), "network");
+        assert!(error.is_transient());
+
+        // accepted by the backlog but never answered
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let error = client.get(url).send().await.unwrap_err();
+        assert!(error.is_timeout());
+        assert_eq!(PixivError::from(Error::from(error)).kind(), "network");
+    }
+
+    #[test]
+    fn from_malformed_json() {
+        let body = r#"{"error":false,"body":{"illustId":"#;
+        let error = serde_json::from_str::<serde_json::Value>(body).unwrap_err();
+        assert_eq!(PixivError::from(Error::from(error)).kind(), "parse");
+
+        let error = serde_json::from_str::<serde_json::Value>(body).unwrap_err();
+        let error = PixivError::from(Error::UnexpectedResponse(error, body.to_string()));
+        assert_eq!(error.kind(), "parse");
+        assert!(!error.is_transient());
+    }
+}
@@ -0,0 +1,326 @@
+use std::{collections::HashMap, fs::File, io, path::Path, process::exit, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use parquet::{
+    basic::Compression,
+    data_type::{BoolType, ByteArray, ByteArrayType, DataType, Int64Type},
+    errors::ParquetError,
+    file::{
+        properties::WriterProperties,
+        writer::{SerializedFileWriter, SerializedRowGroupWriter},
+    },
+    schema::parser::parse_message_type,
+};
+use post_archiver::{PostId, manager::PostArchiverManager};
+use post_archiver_utils::get_post_path;
+
+use crate::{
+    config::{ExportArgs, ExportFormat, PixivTarget, parse_target},
+    inspect,
+};
+
+/// Metadata of an archived post, one row of the export
+#[derive(Debug, Clone)]
+struct ExportedPost {
+    id: u64,
+    pixiv_id: Option<u64>,
+    source: Option<String>,
+    title: String,
+    author_id: Option<u64>,
+    author: Option<String>,
+    tags: Vec<String>,
+    published: Option<DateTime<Utc>>,
+    updated: Option<DateTime<Utc>>,
+    bookmarks: Option<u64>,
+    likes: Option<u64>,
+    views: Option<u64>,
+    comments: Option<u64>,
+    ai: bool,
+}
+
+const COLUMNS: [&str; 14] = [
+    "id",
+    "pixiv_id",
+    "source",
+    "title",
+    "author_id",
+    "author",
+    "tags",
+    "published",
+    "updated",
+    "bookmarks",
+    "likes",
+    "views",
+    "comments",
+    "ai",
+];
+
+/// Same columns as [`COLUMNS`], tags are joined like in the csv
+const PARQUET_SCHEMA: &str = "
+message post {
+    optional int64 id;
+    optional int64 pixiv_id;
+    optional binary source (UTF8);
+    optional binary title (UTF8);
+    optional int64 author_id;
+    optional binary author (UTF8);
+    optional binary tags (UTF8);
+    optional int64 published (TIMESTAMP(MILLIS,true));
+    optional int64 updated (TIMESTAMP(MILLIS,true));
+    optional int64 bookmarks;
+    optional int64 likes;
+    optional int64 views;
+    optional int64 comments;
+    optional boolean ai;
+}";
+
+/// Dump the metadata of the archived posts, for analysis in pandas, duckdb or a spreadsheet
+pub fn export(args: &ExportArgs) {
+    let manager = inspect::open(&args.inspect);
+    let posts = match load_posts(&manager, &args.inspect.output) {
+        Ok(posts) => posts,
+        Err(e) => {
+            error!("[export] Failed to read the archive: {e}");
+            exit(1);
+        }
+    };
+
+    let posts = posts
+        .into_iter()
+        .filter(|post| {
+            args.author.is_empty() || post.author_id.is_some_and(|id| args.author.contains(&id))
+        })
+        .filter(|post| match (args.since, post.published) {
+            (Some(since), Some(published)) => published >= since,
+            (Some(_), None) => false,
+            (None, _) => true,
+        })
+        .filter(|post| match (args.until, post.published) {
+            (Some(until), Some(published)) => published <= until,
+            (Some(_), None) => false,
+            (None, _) => true,
+        })
+        .collect::<Vec<_>>();
+
+    let result = match (args.format, &args.file) {
+        (ExportFormat::Csv, Some(path)) => {
+            File::create(path).and_then(|file| write_csv(file, &posts))
+        }
+        (ExportFormat::Csv, None) => write_csv(io::stdout().lock(), &posts),
+        (ExportFormat::Parquet, Some(path)) => {
+            write_parquet(path, &posts).map_err(io::Error::other)
+        }
+        (ExportFormat::Parquet, None) => unreachable!("--file is required for parquet"),
+    };
+
+    match (result, &args.file) {
+        (Ok(()), Some(path)) => {
+            info!("[export] Exported {} posts to {}", posts.len(), path.display())
+        }
+        (Ok(()), None) => {}
+        (Err(e), _) => {
+            error!("[export] Failed to export: {e}");
+            exit(1);
+        }
+    }
+}
+
+fn load_posts(
+    manager: &PostArchiverManager,
+    root: &Path,
+) -> rusqlite::Result<Vec<ExportedPost>> {
+    let conn = manager.conn();
+
+    let mut authors = HashMap::<u64, (Option<u64>, String)>::new();
+    let mut stmt = conn.prepare(
+        "SELECT author_posts.post, author_aliases.source, authors.name FROM author_posts
+        JOIN authors ON authors.id = author_posts.author
+        LEFT JOIN author_aliases ON author_aliases.target = authors.id
+            AND author_aliases.platform = (SELECT id FROM platforms WHERE name = 'pixiv')",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, u64>(0)?,
+            row.get::<_, Option<String>>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+    for row in rows {
+        let (post, source, name) = row?;
+        // pixiv works have a single author, keep the first one of posts merged by hand
+        authors
+            .entry(post)
+            .or_insert((source.and_then(|source| source.parse().ok()), name));
+    }
+
+    let mut tags = HashMap::<u64, Vec<String>>::new();
+    let mut stmt = conn.prepare(
+        "SELECT post_tags.post, tags.name FROM post_tags JOIN tags ON tags.id = post_tags.tag",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, u64>(0)?, row.get(1)?)))?;
+    for row in rows {
+        let (post, name) = row?;
+        tags.entry(post).or_default().push(name);
+    }
+
+    // counts recorded by the archive, see `storage::Metadata::Stats`
+    let mut stats = HashMap::<u64, serde_json::Value>::new();
+    let has_stats = conn
+        .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'pixiv_metadata'")?
+        .exists([])?;
+    if has_stats {
+        let mut stmt =
+            conn.prepare("SELECT post, value FROM pixiv_metadata WHERE kind = 'stats'")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, u64>(0)?, row.get::<_, String>(1)?)))?;
+        for row in rows {
+            let (post, value) = row?;
+            if let Ok(value) = serde_json::from_str(&value) {
+                stats.insert(post, value);
+            }
+        }
+    }
+
+    let mut stmt =
+        conn.prepare("SELECT id, source, title, published, updated FROM posts ORDER BY id")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, u64>(0)?,
+            row.get::<_, PostId>(0)?,
+            row.get::<_, Option<String>>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, Option<String>>(3)?,
+            row.get::<_, Option<String>>(4)?,
+        ))
+    })?;
+
+    let mut posts = vec![];
+    for row in rows {
+        let (id, post, source, title, published, updated) = row?;
+        let (author_id, author) = authors.remove(&id).unzip();
+        let tags = tags.remove(&id).unwrap_or_default();
+        // posts archived before the table have the `stats.json` sidecar instead
+        let stats = stats
+            .remove(&id)
+            .or_else(|| read_stats(&get_post_path(root, post).join("stats.json")));
+        let count = |key: &str| stats.as_ref().and_then(|stats| stats[key].as_u64());
+
+        posts.push(ExportedPost {
+            id,
+            pixiv_id: source.as_deref().and_then(|source| match parse_target(source) {
+                Ok(PixivTarget::Artwork(artwork)) => artwork.id(),
+                _ => None,
+            }),
+            source,
+            title,
+            author_id: author_id.flatten(),
+            author,
+            // same label as shown on pixiv
+            ai: tags.iter().any(|tag| tag == "AI生成"),
+            tags,
+            published: published.and_then(|date| date.parse().ok()),
+            updated: updated.and_then(|date| date.parse().ok()),
+            bookmarks: count("bookmarks"),
+            likes: count("likes"),
+            views: count("views"),
+            comments: count("comments"),
+        });
+    }
+    Ok(posts)
+}
+
+/// Counts of the `stats.json` sidecar of older archives
+fn read_stats(path: &Path) -> Option<serde_json::Value> {
+    let content = std::fs::read_to_string(path).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+fn write_csv(writer: impl io::Write, posts: &[ExportedPost]) -> io::Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(COLUMNS)?;
+
+    fn optional<T: ToString>(value: Option<T>) -> String {
+        value.map(|value| value.to_string()).unwrap_or_default()
+    }
+    for post in posts {
+        writer.write_record([
+            post.id.to_string(),
+            optional(post.pixiv_id),
+            optional(post.source.as_ref()),
+            post.title.clone(),
+            optional(post.author_id),
+            optional(post.author.as_ref()),
+            post.tags.join(", "),
+            optional(post.published.map(|date| date.to_rfc3339())),
+            optional(post.updated.map(|date| date.to_rfc3339())),
+            optional(post.bookmarks),
+            optional(post.likes),
+            optional(post.views),
+            optional(post.comments),
+            post.ai.to_string(),
+        ])?;
+    }
+    writer.flush()
+}
+
+fn write_parquet(path: &Path, posts: &[ExportedPost]) -> Result<(), ParquetError> {
+    let schema = Arc::new(parse_message_type(PARQUET_SCHEMA)?);
+    let properties = Arc::new(
+        WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build(),
+    );
+    let mut writer = SerializedFileWriter::new(File::create(path)?, schema, properties)?;
+    let mut row_group = writer.next_row_group()?;
+
+    let text = |value: Option<&String>| value.map(|value| ByteArray::from(value.as_str()));
+    let int = |value: Option<u64>| value.map(|value| value as i64);
+    let millis = |value: Option<DateTime<Utc>>| value.map(|value| value.timestamp_millis());
+
+    // in the order of the schema
+    let rows = &mut row_group;
+    write_column::<Int64Type>(rows, posts.iter().map(|post| int(Some(post.id))))?;
+    write_column::<Int64Type>(rows, posts.iter().map(|post| int(post.pixiv_id)))?;
+    write_column::<ByteArrayType>(rows, posts.iter().map(|post| text(post.source.as_ref())))?;
+    write_column::<ByteArrayType>(rows, posts.iter().map(|post| text(Some(&post.title))))?;
+    write_column::<Int64Type>(rows, posts.iter().map(|post| int(post.author_id)))?;
+    write_column::<ByteArrayType>(rows, posts.iter().map(|post| text(post.author.as_ref())))?;
+    write_column::<ByteArrayType>(
+        rows,
+        posts.iter().map(|post| text(Some(&post.tags.join(", ")))),
+    )?;
+    write_column::<Int64Type>(rows, posts.iter().map(|post| millis(post.published)))?;
+    write_column::<Int64Type>(rows, posts.iter().map(|post| millis(post.updated)))?;
+    write_column::<Int64Type>(rows, posts.iter().map(|post| int(post.bookmarks)))?;
+    write_column::<Int64Type>(rows, posts.iter().map(|post| int(post.likes)))?;
+    write_column::<Int64Type>(rows, posts.iter().map(|post| int(post.views)))?;
+    write_column::<Int64Type>(rows, posts.iter().map(|post| int(post.comments)))?;
+    write_column::<BoolType>(rows, posts.iter().map(|post| Some(post.ai)))?;
+
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+/// Write the next column of the row group, `None` values are stored as nulls
+fn write_column<T: DataType>(
+    row_group: &mut SerializedRowGroupWriter<'_, File>,
+    values: impl Iterator<Item = Option<T::T>>,
+) -> Result<(), ParquetError> {
+    let mut present = vec![];
+    let levels = values
+        .map(|value| match value {
+            Some(value) => {
+                present.push(value);
+                1
+            }
+            None => 0,
+        })
+        .collect::<Vec<i16>>();
+
+    let mut column = row_group
+        .next_column()?
+        .ok_or_else(|| ParquetError::General("more columns than the schema".to_string()))?;
+    column.typed::<T>().write_batch(&present, Some(&levels), None)?;
+    column.close()
+}
@@ -0,0 +1,68 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use log::warn;
+
+/// Works which failed to archive with the kind of their error, saved as `failed.json`
+/// and retried by `--retry-failed`
+#[derive(Debug, Clone, Default)]
+pub struct FailedWorks {
+    path: PathBuf,
+    inner: Arc<Mutex<BTreeMap<String, String>>>,
+}
+
+impl FailedWorks {
+    pub fn open(root: &Path) -> Self {
+        let path = root.join("failed.json");
+        let inner = fs::read(&path)
+            .ok()
+            .and_then(|data| {
+                serde_json::from_slice(&data)
+                    .inspect_err(|e| warn!("[failed] Ignoring invalid {}: {e}", path.display()))
+                    .ok()
+            })
+            .unwrap_or_default();
+
+        Self {
+            path,
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// urls of the failed works, as accepted on the command line
+    pub fn urls(&self) -> Vec<String> {
+        self.inner.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn contains(&self, url: &str) -> bool {
+        self.inner.lock().unwrap().contains_key(url)
+    }
+
+    pub fn record(&self, url: &str, kind: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.insert(url.to_string(), kind.to_string());
+    }
+
+    pub fn remove(&self, url: &str) {
+        self.inner.lock().unwrap().remove(url);
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if self.path.as_os_str().is_empty() {
+            return Ok(());
+        }
+
+        let inner = self.inner.lock().unwrap();
+        if inner.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        fs::write(&self.path, serde_json::to_vec_pretty(&*inner)?)
+    }
+}
@@ -1,7 +1,24 @@
-use crate::{Config, api::PixivClient, artwork::PixivArtworkId, user::PixivUserId};
+use std::{
+    collections::{BTreeSet, HashSet},
+    sync::Arc,
+};
+
+use crate::{
+    Config, Manager,
+    api::PixivClient,
+    artwork::PixivArtworkId,
+    checkpoint::Checkpoint,
+    deleted::DeletedWorks,
+    prune::{Listed, prune},
+    error::{PixivError, PixivResult},
+    storage::Storage,
+    user::{NewestWorks, PixivUserId, UserSync},
+};
 
 use log::{debug, error, info, warn};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
 use plyne::Input;
+use post_archiver_utils::Result;
 use serde::Deserialize;
 use tokio::task::JoinSet;
 
@@ -17,23 +34,61 @@ pub struct PixivUserStatus {
     pub user_id: u64,
 }
 
+/// Id of the logged in user, failing with [`PixivError::Auth`] when the session is expired
+pub async fn fetch_current_user(client: &PixivClient) -> PixivResult<u64> {
+    match client
+        .fetch::<PixivUserStatusOuter>("https://www.pixiv.net/ajax/settings/self")
+        .await
+    {
+        Ok(response) => Ok(response.user_status.user_id),
+        // logged out sessions get a user status without an id
+        Err(PixivError::Parse(_)) => Err(PixivError::Auth),
+        Err(e) => Err(e),
+    }
+}
+
+/// Works already archived, to stop paging newest-first lists early with `--incremental`.
+///
+/// Followed users are listed in follow order instead, they are skipped by their newest
+/// works (see [`UserSync`]) rather than cut by a streak.
+#[derive(Debug, Clone)]
+pub struct Incremental {
+    posts: Arc<HashSet<String>>,
+    after: usize,
+}
+
+impl Incremental {
+    pub fn load(storage: &dyn Storage, after: usize) -> Result<Self> {
+        Ok(Self {
+            posts: Arc::new(storage.sources()?),
+            after,
+        })
+    }
+
+    /// Count the item into the streak of archived ones, true once the streak is long enough to stop
+    fn reached(&self, streak: &mut usize, archived: bool) -> bool {
+        *streak = match archived {
+            true => *streak + 1,
+            false => 0,
+        };
+        *streak >= self.after.max(1)
+    }
+}
+
 pub async fn reslove_current_user(
     users_pipeline: Input<PixivUserId>,
     artworks_pipeline: Input<PixivArtworkId>,
+    manager: &Manager,
     client: &PixivClient,
     config: &Config,
 ) {
-    if !(config.favorite || config.followed_users) {
+    if !(config.favorite || config.followed_users || config.following_feed.is_some()) {
         debug!("[current_user] Skipping favorites and following users archiving");
         return;
     }
 
-    let user = match client.fetch::<PixivUserStatusOuter>(
-        "https://www.pixiv.net/ajax/settings/self",
-    )
-    .await
-    {
-        Ok(response) => response.user_status.user_id,
+    let user = match fetch_current_user(client).await {
+        Ok(user) => user,
         Err(e) => {
             error!("[current_user] Failed to fetch current user: {e:?}");
             return;
@@ -42,21 +97,137 @@ pub async fn reslove_current_user(
 
     info!("[current_user] Current user ID: {user}");
 
+    let incremental = match config.incremental {
+        Some(after) => Incremental::load(&**manager.lock().await, after)
+            .inspect_err(|e| warn!("[current_user] Failed to load archived works: {e}"))
+            .ok(),
+        None => None,
+    };
+
     let mut join_set = JoinSet::new();
     if config.followed_users {
         info!("[following] Archiving followed users");
-        join_set.spawn(reslove_following(users_pipeline, client.clone(), user));
+        join_set.spawn(reslove_following(
+            users_pipeline,
+            client.clone(),
+            user,
+            UserSync::open(manager, config).await,
+        ));
+    }
+
+    if let Some(pages) = config.following_feed {
+        for ty in ["illust", "novel"] {
+            info!("[following] Fetching latest {ty}s of followed users");
+            let tx_artwork = artworks_pipeline.clone();
+            let feed = reslove_following_feed(
+                tx_artwork,
+                client.clone(),
+                config.checkpoint.clone(),
+                ty,
+                pages,
+                incremental.clone(),
+            );
+            join_set.spawn(async move {
+                feed.await;
+                None
+            });
+        }
     }
 
     if config.favorite {
         for ty in ["illusts", "novels"] {
-            info!("[favorite] Fetching favorites of {ty}");
-            let tx_artwork = artworks_pipeline.clone();
-            join_set.spawn(reslove_favorite(tx_artwork, client.clone(), ty, user));
+            let tags = if config.favorite_tags.is_empty() {
+                vec![String::new()]
+            } else {
+                validate_favorite_tags(client, ty, user, &config.favorite_tags).await
+            };
+
+            for tag in tags {
+                info!("[favorite] Fetching favorites of {ty} (tag: {tag:?})");
+                let tx_artwork = artworks_pipeline.clone();
+                join_set.spawn(reslove_favorite(
+                    tx_artwork,
+                    client.clone(),
+                    config.checkpoint.clone(),
+                    config.deleted.clone(),
+                    ty,
+                    user,
+                    tag,
+                    incremental.clone(),
+                ));
+            }
         }
     }
 
-    join_set.join_all().await;
+    let listed = join_set.join_all().await;
+
+    let Some(mode) = config.prune else {
+        return;
+    };
+    let mut favorites = BTreeSet::new();
+    let mut listed_favorites = 0;
+    let mut following = None;
+    for listed in listed.into_iter().flatten() {
+        match listed {
+            Listed::Favorites(urls) => {
+                favorites.extend(urls);
+                listed_favorites += 1;
+            }
+            Listed::Following(users) => following = Some(users.into_iter().collect()),
+        }
+    }
+    // both illusts and novels, without --favorite-tags which lists only some of them
+    let favorites =
+        (listed_favorites == 2 && config.favorite_tags.is_empty()).then_some(favorites);
+    prune(manager, &config.output, mode, favorites, following).await;
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PixivBookmarkTags {
+    #[serde(default)]
+    pub public: Vec<PixivBookmarkTag>,
+    #[serde(default)]
+    pub private: Vec<PixivBookmarkTag>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PixivBookmarkTag {
+    pub tag: String,
+    pub cnt: usize,
+}
+
+/// Keep only the requested tags that exist in the user's bookmark tags
+async fn validate_favorite_tags(
+    client: &PixivClient,
+    ty: &str,
+    user: u64,
+    tags: &[String],
+) -> Vec<String> {
+    let url = format!("https://www.pixiv.net/ajax/user/{user}/{ty}/bookmark/tags?lang=ja");
+    let bookmark_tags = match client.fetch::<PixivBookmarkTags>(&url).await {
+        Ok(bookmark_tags) => bookmark_tags,
+        Err(e) => {
+            error!("[favorite] Failed to fetch bookmark tags of {ty}: {e:?}");
+            return vec![];
+        }
+    };
+
+    tags.iter()
+        .filter(|tag| {
+            let found = bookmark_tags.public.iter().find(|known| &known.tag == *tag);
+            match found {
+                Some(known) => {
+                    debug!("[favorite] Bookmark tag {tag:?} has {} {ty}", known.cnt);
+                    true
+                }
+                None => {
+                    warn!("[favorite] No bookmarked {ty} under tag {tag:?}, skipping");
+                    false
+                }
+            }
+        })
+        .cloned()
+        .collect()
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -77,51 +248,95 @@ pub enum PixivFavoriteWorkId {
     Unreachable(u64),
 }
 
+/// Returns the favorites when it went through all of them, for `--prune`
 pub async fn reslove_favorite(
     tx: Input<PixivArtworkId>,
     client: PixivClient,
+    checkpoint: Checkpoint,
+    deleted: DeletedWorks,
     ty: &'static str,
     user: u64,
-) {
-    let mut page = 0;
-    let mut total = 1;
+    tag: String,
+    incremental: Option<Incremental>,
+) -> Option<Listed> {
     const LIMIT: usize = 100;
+    let cursor = format!("favorite:{ty}:{tag}");
+    let tag = utf8_percent_encode(&tag, NON_ALPHANUMERIC);
 
-    let mut offset = 0;
-    while offset <= total {
-        offset = page * LIMIT;
-        page += 1;
-
+    let mut offset = checkpoint.cursor(&cursor).unwrap_or(0);
+    if offset > 0 {
+        info!("[favorite] Resuming {ty} from offset {offset}");
+    }
+    let resumed = offset > 0;
+    let mut total: Option<usize> = None;
+    let mut streak = 0;
+    let mut listed = vec![];
+    loop {
         let url = format!(
-            "https://www.pixiv.net/ajax/user/{user}/{ty}/bookmarks?tag=&offset={offset}&limit={LIMIT}&rest=show"
+            "https://www.pixiv.net/ajax/user/{user}/{ty}/bookmarks?tag={tag}&offset={offset}&limit={LIMIT}&rest=show"
         );
 
         let response = match client.fetch::<PixivFavorite>(&url).await {
             Ok(response) => response,
             Err(e) => {
                 error!("[favorite] Failed to fetch {ty}: {e:?}");
-                return;
+                return None;
             }
         };
-        total = response.total;
 
+        if let Some(previous) = total
+            && previous != response.total
+        {
+            warn!(
+                "[favorite] Total of {ty} changed from {previous} to {} while paging",
+                response.total
+            );
+
+            if let Some(rewound) = rewind(offset, previous, response.total) {
+                offset = rewound;
+                total = Some(response.total);
+                continue;
+            }
+        }
+        total = Some(response.total);
+
+        let count = response.works.len();
         for artwork in response.works {
-            let id = match artwork.id {
-                PixivFavoriteWorkId::Common(id) => id.parse::<u64>().unwrap(),
-                PixivFavoriteWorkId::Unreachable(id) => {
-                    warn!("[favorite] Unreachable favorite artwork {id}, skipping");
-                    continue;
-                }
+            let (id, reachable) = match artwork.id {
+                PixivFavoriteWorkId::Common(id) => (id.parse::<u64>().unwrap(), true),
+                PixivFavoriteWorkId::Unreachable(id) => (id, false),
             };
             let id = match ty {
                 "illusts" => PixivArtworkId::Illust(id),
                 "novels" => PixivArtworkId::Novel(id),
                 _ => unreachable!("Invalid type for favorite: {ty}"),
             };
+            listed.push(id.url());
+            if !reachable {
+                warn!("[favorite] Unreachable favorite artwork {id:?}, skipping");
+                deleted.record(&id.url(), "unreachable favorite", None);
+                continue;
+            }
+            if let Some(incremental) = &incremental
+                && incremental.reached(&mut streak, incremental.posts.contains(&id.url()))
+            {
+                info!("[favorite] Reached {streak} archived {ty} in a row, stopping");
+                return None;
+            }
             info!("[favorite] Archive favorite artwork: {id:?}");
+            // pending before the cursor moves past it
+            checkpoint.add(id.url());
             tx.send(id).unwrap();
         }
+
+        offset += LIMIT;
+        checkpoint.set_cursor(&cursor, offset);
+        if count == 0 || offset >= response.total {
+            break;
+        }
     }
+
+    (!resumed).then_some(Listed::Favorites(listed))
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -134,10 +349,39 @@ pub struct PixivFollowing {
 #[serde(rename_all = "camelCase")]
 pub struct PixivFollowingUser {
     pub user_id: u64,
+    /// latest works of the user
+    #[serde(default)]
+    pub illusts: Vec<PixivFollowingWork>,
+    #[serde(default)]
+    pub novels: Vec<PixivFollowingWork>,
 }
-pub async fn reslove_following(tx: Input<PixivUserId>, client: PixivClient, user: u64) {
+
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct PixivFollowingWork {
+    #[serde_as(as = "serde_with::PickFirst<(_, serde_with::DisplayFromStr)>")]
+    pub id: u64,
+}
+
+impl PixivFollowingUser {
+    pub fn newest(&self) -> NewestWorks {
+        let newest = |works: &[PixivFollowingWork]| works.iter().map(|work| work.id).max();
+        NewestWorks {
+            illust: newest(&self.illusts),
+            novel: newest(&self.novels),
+        }
+    }
+}
+/// Returns the followed users when it went through all of them, for `--prune`
+pub async fn reslove_following(
+    tx: Input<PixivUserId>,
+    client: PixivClient,
+    user: u64,
+    sync: Option<UserSync>,
+) -> Option<Listed> {
     let mut page = 0;
     let mut total = 1;
+    let mut listed = vec![];
     const LIMIT: usize = 100;
 
     info!("[following] Fetching following user");
@@ -154,13 +398,106 @@ pub async fn reslove_following(tx: Input<PixivUserId>, client: PixivClient, user
             Ok(response) => response,
             Err(e) => {
                 error!("[following] Failed to fetch following user: {e:?}");
-                return;
+                return None;
             }
         };
         total = response.total;
-        for PixivFollowingUser { user_id } in response.users.iter() {
+        for following in response.users.iter() {
+            let user_id = following.user_id;
+            listed.push(user_id);
+            // saves the profile request of the users without new works
+            if let Some(sync) = &sync
+                && sync.is_unchanged(user_id, following.newest())
+            {
+                debug!("[following] No new works of {user_id} since the last run, skipping");
+                continue;
+            }
             info!("[following] Found following user: {user_id}");
-            tx.send(*user_id).unwrap();
+            tx.send(user_id).unwrap();
+        }
+    }
+    Some(Listed::Following(listed))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PixivFollowLatest {
+    pub page: PixivFollowLatestPage,
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct PixivFollowLatestPage {
+    #[serde_as(as = "Vec<serde_with::PickFirst<(_, serde_with::DisplayFromStr)>>")]
+    pub ids: Vec<u64>,
+}
+
+pub async fn reslove_following_feed(
+    tx: Input<PixivArtworkId>,
+    client: PixivClient,
+    checkpoint: Checkpoint,
+    ty: &'static str,
+    pages: u32,
+    incremental: Option<Incremental>,
+) {
+    let cursor = format!("following_feed:{ty}");
+    let mut streak = 0;
+    let first = checkpoint.cursor(&cursor).unwrap_or(0) as u32 + 1;
+    for page in first..=pages {
+        let url = format!("https://www.pixiv.net/ajax/follow_latest/{ty}?p={page}&mode=all&lang=ja");
+
+        let response = match client.fetch::<PixivFollowLatest>(&url).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("[following] Failed to fetch latest {ty}s: {e:?}");
+                return;
+            }
+        };
+
+        if response.page.ids.is_empty() {
+            break;
         }
+
+        for id in response.page.ids {
+            let id = match ty {
+                "illust" => PixivArtworkId::Illust(id),
+                "novel" => PixivArtworkId::Novel(id),
+                _ => unreachable!("Invalid type for following feed: {ty}"),
+            };
+            if let Some(incremental) = &incremental
+                && incremental.reached(&mut streak, incremental.posts.contains(&id.url()))
+            {
+                info!("[following] Reached {streak} archived {ty}s in a row, stopping");
+                return;
+            }
+            checkpoint.add(id.url());
+            tx.send(id).unwrap();
+        }
+        checkpoint.set_cursor(&cursor, page as usize);
+    }
+}
+
+/// Offset to read again when the total changed between two pages, `None` to go on.
+/// Bookmarks are newest-first, so removed works shift the rest forward: step back and
+/// re-read the page so none of them are skipped. Added works only repeat listed ones.
+fn rewind(offset: usize, previous: usize, total: usize) -> Option<usize> {
+    (total < previous).then(|| offset.saturating_sub(previous - total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewind_on_shrinking_total() {
+        // 3 works removed while reading the page at 200
+        assert_eq!(rewind(200, 500, 497), Some(197));
+        // never before the first page
+        assert_eq!(rewind(2, 500, 490), Some(0));
+    }
+
+    #[test]
+    fn no_rewind_on_growing_total() {
+        assert_eq!(rewind(200, 500, 503), None);
+        assert_eq!(rewind(200, 500, 500), None);
     }
 }
@@ -1,12 +1,20 @@
-use std::{sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use fast_image_resize::{ResizeOptions, Resizer};
-use futures::future::try_join_all;
-use image::{DynamicImage, ImageReader};
-use log::{error, warn};
+use flate2::{Compression, write::GzEncoder};
+use futures::{StreamExt, TryStreamExt, stream};
+use image::{
+    Delay, DynamicImage, Frame, ImageError, ImageFormat, ImageReader, Limits,
+    codecs::gif::{GifEncoder, Repeat},
+};
+use log::{error, trace, warn};
 use plyne::Output;
+use post_archiver::importer::UnsyncFileMeta;
 use post_archiver_utils::Result;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tempfile::TempPath;
 use tokio::{sync::Semaphore, task::JoinSet};
 use std::fmt::Write;
@@ -14,7 +22,7 @@ use std::fmt::Write;
 use crate::{
     FileEvent,
     api::PixivClient,
-    config::{Config, Progress},
+    config::{Config, Progress, ResizeMode, TranscodeFormat, UgoiraFormat},
 };
 
 #[derive(Debug, Clone, Deserialize)]
@@ -28,7 +36,42 @@ pub enum ArchiveRequest {
     Ugoira {
         url: String,
         frames: Vec<PixivUgoiraFrame>,
+        format: UgoiraFormat,
     },
+    UgoiraPoster {
+        url: String,
+        frame: String,
+    },
+    /// Image converted to a smaller format, see `--transcode`
+    Transcode {
+        url: String,
+        format: TranscodeFormat,
+        quality: u8,
+    },
+    /// Jpeg downscaled to fit `size`, see `--thumbnails`
+    Thumbnail {
+        url: String,
+        size: u32,
+    },
+    /// File stored exactly as downloaded, even if the url is also converted by another request
+    Original(String),
+    /// Image that falls back to another url (e.g. a smaller size) when unavailable
+    ImageWithFallback {
+        url: String,
+        fallback: String,
+    },
+    /// Generated file, written from the content instead of downloaded
+    Sidecar {
+        name: String,
+        content: String,
+    },
+    /// Generated file, written from the content compressed with gzip
+    Gzip {
+        name: String,
+        content: Vec<u8>,
+    },
+    /// File already archived on disk, nothing to download
+    Existing(String),
 }
 
 impl ArchiveRequest {
@@ -37,10 +80,73 @@ impl ArchiveRequest {
             ArchiveRequest::Image(url) => url,
             ArchiveRequest::ImageWithSize { url, .. } => url,
             ArchiveRequest::Ugoira { url, .. } => url,
+            ArchiveRequest::UgoiraPoster { url, .. } => url,
+            ArchiveRequest::Transcode { url, .. } => url,
+            ArchiveRequest::Thumbnail { url, .. } => url,
+            ArchiveRequest::Original(url) => url,
+            ArchiveRequest::ImageWithFallback { url, .. } => url,
+            ArchiveRequest::Sidecar { name, .. } => name,
+            ArchiveRequest::Gzip { name, .. } => name,
+            ArchiveRequest::Existing(path) => path,
+        }
+    }
+
+    /// Key of the downloaded file, unique even when requests share the same url
+    pub fn key(&self) -> String {
+        match self {
+            ArchiveRequest::UgoiraPoster { url, .. } => format!("{url}#poster"),
+            ArchiveRequest::Thumbnail { url, .. } => format!("{url}#thumb"),
+            ArchiveRequest::Sidecar { name, .. } | ArchiveRequest::Gzip { name, .. } => {
+                format!("sidecar:{name}")
+            }
+            ArchiveRequest::Original(url) => format!("{url}#original"),
+            _ => self.url().to_string(),
         }
     }
 }
 
+/// Replace the characters not allowed in file names
+pub fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// A json file generated alongside the post
+pub fn json_sidecar(name: &str, value: &serde_json::Value) -> UnsyncFileMeta<ArchiveRequest> {
+    UnsyncFileMeta::new(
+        name.to_string(),
+        "application/json".to_string(),
+        ArchiveRequest::Sidecar {
+            name: name.to_string(),
+            content: serde_json::to_string_pretty(value).unwrap(),
+        },
+    )
+}
+
+/// A gzipped file generated alongside the post, e.g. an untouched api response
+pub fn gzip_sidecar(name: &str, content: Vec<u8>) -> UnsyncFileMeta<ArchiveRequest> {
+    UnsyncFileMeta::new(
+        name.to_string(),
+        "application/gzip".to_string(),
+        ArchiveRequest::Gzip {
+            name: name.to_string(),
+            content,
+        },
+    )
+}
+
+pub fn gzip(content: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    std::io::Write::write_all(&mut encoder, content)?;
+    encoder.finish()
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct PixivUgoira {
     pub src: String,
@@ -50,18 +156,52 @@ pub struct PixivUgoira {
     pub frames: Vec<PixivUgoiraFrame>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct PixivUgoiraFrame {
     pub delay: u32,
     pub file: String,
 }
 
+/// Options of the files pipeline, copied out of [`Config`] for the download tasks
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    pub max_decode_pixels: u64,
+    pub resize: ResizeMode,
+    pub validate: bool,
+    /// files of a post downloaded at once
+    pub page_concurrency: usize,
+    /// ffmpeg binary and extra encoder args, `None` uses the internal gif encoder
+    pub ffmpeg: Option<(PathBuf, Vec<String>)>,
+}
+
+impl From<&Config> for DownloadOptions {
+    fn from(config: &Config) -> Self {
+        Self {
+            max_decode_pixels: config.max_decode_pixels,
+            resize: config.resize,
+            validate: config.resume_downloads,
+            page_concurrency: config.page_concurrency.unwrap_or(usize::MAX),
+            ffmpeg: config.has_ffmpeg.then(|| {
+                let args = config
+                    .ffmpeg_args
+                    .as_deref()
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect();
+                (config.ffmpeg.clone(), args)
+            }),
+        }
+    }
+}
+
 pub async fn download_files(mut files_pipeline: Output<FileEvent>, config: &Config) {
-    let files_pb = Progress::new(config.multi.clone(), "files");
+    let files_pb = Progress::new(config, "files");
+    let options = Arc::new(DownloadOptions::from(config));
 
     let mut tasks = JoinSet::new();
     let client = PixivClient::new(config);
-    let semaphore = Arc::new(Semaphore::new(3));
+    let semaphore = Arc::new(Semaphore::new(config.download_concurrency.max(1)));
     while let Some((reqs, tx)) = files_pipeline.recv().await {
         if reqs.is_empty() {
             tx.send(Default::default()).unwrap();
@@ -71,18 +211,21 @@ pub async fn download_files(mut files_pipeline: Output<FileEvent>, config: &Conf
         let semaphore = semaphore.clone();
         let files_pb = files_pb.clone();
         let client = client.clone();
+        let options = options.clone();
         files_pb.inc_length(reqs.len() as u64);
         tasks.spawn(async move {
             let _permit = semaphore.acquire().await.unwrap();
-            match try_join_all(reqs.into_iter().map(async |req| {
-                let url = req.url().to_string();
-                let result = download_file(req, &client).await.map(|dst| (url, dst));
-                files_pb.inc(1);
-                result
-            }))
-            .await
-            {
-                Ok(results) => tx.send(results.into_iter().collect()).unwrap(),
+            let (client, options, files_pb) = (&client, &options, &files_pb);
+            let downloads = stream::iter(group_by_url(reqs))
+                .map(|reqs| async move {
+                    let count = reqs.len() as u64;
+                    let result = download_group(reqs, client, options).await;
+                    files_pb.inc(count);
+                    result
+                })
+                .buffer_unordered(options.page_concurrency.max(1));
+            match downloads.try_collect::<Vec<_>>().await {
+                Ok(results) => tx.send(results.into_iter().flatten().collect()).unwrap(),
                 Err(e) => error!("Failed to download files: {e}"),
             }
         });
@@ -92,20 +235,132 @@ pub async fn download_files(mut files_pipeline: Output<FileEvent>, config: &Conf
     files_pb.finish();
 }
 
-async fn download_file(request: ArchiveRequest, client: &PixivClient) -> Result<TempPath> {
-    let dst = client.as_inner().download(request.url()).await?;
+/// Requests grouped by the url they download, generated files are alone in their group
+fn group_by_url(reqs: Vec<ArchiveRequest>) -> Vec<Vec<ArchiveRequest>> {
+    let mut groups: Vec<(Option<String>, Vec<ArchiveRequest>)> = vec![];
+    for req in reqs {
+        let url = match req {
+            ArchiveRequest::Sidecar { .. } | ArchiveRequest::Gzip { .. } => None,
+            _ => Some(req.url().to_string()),
+        };
+        match groups.iter_mut().find(|(key, _)| url.is_some() && *key == url) {
+            Some((_, group)) => group.push(req),
+            None => groups.push((url, vec![req])),
+        }
+    }
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+/// Download the file of the requests once (e.g. an ugoira zip, its poster and the kept zip),
+/// then convert a copy of it for each of them
+async fn download_group(
+    requests: Vec<ArchiveRequest>,
+    client: &PixivClient,
+    options: &DownloadOptions,
+) -> Result<Vec<(String, TempPath)>> {
+    let first = &requests[0];
+    let dst = match first {
+        ArchiveRequest::Sidecar { content, .. } => {
+            let mut file = tempfile::NamedTempFile::new()?;
+            std::io::Write::write_all(&mut file, content.as_bytes())?;
+            file.into_temp_path()
+        }
+        ArchiveRequest::Gzip { content, .. } => {
+            let mut file = tempfile::NamedTempFile::new()?;
+            std::io::Write::write_all(&mut file, &gzip(content)?)?;
+            file.into_temp_path()
+        }
+        _ => {
+            let url = first.url();
+            let fallback = requests.iter().find_map(|request| match request {
+                ArchiveRequest::ImageWithFallback { fallback, .. } => Some(fallback),
+                _ => None,
+            });
+            let dst = match (client.download(url).await, fallback) {
+                (Ok(dst), _) => dst,
+                (Err(e), Some(fallback)) => {
+                    warn!("Failed to download {url} ({e}), falling back to {fallback}");
+                    client.download(fallback).await?
+                }
+                (Err(e), None) => return Err(e.into()),
+            };
+            // images with a fallback were never validated
+            if options.validate && fallback.is_none() {
+                validate(&dst, first).map_err(|e| {
+                    error!("Downloaded file {url} is broken: {e}");
+                    post_archiver_utils::Error::InvalidResponse(e.to_string())
+                })?;
+            }
+            dst
+        }
+    };
 
+    let mut dst = Some(dst);
+    let mut requests = requests.into_iter().peekable();
+    let mut results = vec![];
+    while let Some(request) = requests.next() {
+        // the last request takes the download itself, the others a copy of it
+        let src = match requests.peek() {
+            Some(_) => copy_temp(dst.as_ref().unwrap())?,
+            None => dst.take().unwrap(),
+        };
+        let key = request.key();
+        results.push((key, convert(request, src, options).await?));
+    }
+    Ok(results)
+}
+
+fn copy_temp(path: &Path) -> std::io::Result<TempPath> {
+    let copy = tempfile::NamedTempFile::new()?.into_temp_path();
+    std::fs::copy(path, &copy)?;
+    Ok(copy)
+}
+
+async fn convert(
+    request: ArchiveRequest,
+    dst: TempPath,
+    options: &DownloadOptions,
+) -> Result<TempPath> {
     match request {
-        ArchiveRequest::Image(_) => Ok(dst),
+        ArchiveRequest::Image(_)
+        | ArchiveRequest::Original(_)
+        | ArchiveRequest::ImageWithFallback { .. }
+        | ArchiveRequest::Sidecar { .. }
+        | ArchiveRequest::Gzip { .. } => Ok(dst),
         ArchiveRequest::ImageWithSize {
             url: _,
             width,
             height,
-        } => {
+        } => match options.resize {
             // TODO: move resizer to a separate thread
-            resize(dst, width, height)
+            ResizeMode::Reencode => resize(dst, width, height, options.max_decode_pixels),
+            // the real size is recorded when the file is archived
+            ResizeMode::Metadata | ResizeMode::Off => Ok(dst),
+        },
+        ArchiveRequest::Ugoira {
+            url: _,
+            frames,
+            format,
+        } => {
+            let ffmpeg = options.ffmpeg.as_ref();
+            convert_ugoira(dst, frames, format, ffmpeg, options.max_decode_pixels).await
+        }
+        ArchiveRequest::UgoiraPoster { url: _, frame } => extract_ugoira_frame(dst, frame).await,
+        ArchiveRequest::Transcode {
+            url: _,
+            format,
+            quality,
+        } => {
+            let ffmpeg = options.ffmpeg.as_ref().map(|(ffmpeg, _)| ffmpeg);
+            transcode(dst, format, quality, ffmpeg).await
+        }
+        ArchiveRequest::Thumbnail { url: _, size } => {
+            let max_pixels = options.max_decode_pixels;
+            tokio::task::spawn_blocking(move || thumbnail(dst, size, max_pixels))
+                .await
+                .map_err(|_| "Blocking task panicked")?
         }
-        ArchiveRequest::Ugoira { url: _, frames } => convert_ugoira(dst, frames).await,
+        ArchiveRequest::Existing(_) => unreachable!("Existing files are never downloaded"),
     }
     .map_err(|e: &'static str| {
         error!("Failed to process file: {e}");
@@ -116,10 +371,15 @@ async fn download_file(request: ArchiveRequest, client: &PixivClient) -> Result<
 async fn convert_ugoira(
     zip_path: TempPath,
     frames: Vec<PixivUgoiraFrame>,
+    format: UgoiraFormat,
+    ffmpeg: Option<&(PathBuf, Vec<String>)>,
+    max_pixels: u64,
 ) -> std::result::Result<TempPath, &'static str> {
     let temp_dir = tempfile::tempdir().map_err(|_| "Failed to create temp dir for ugoira")?;
     let temp_dir_path = temp_dir.path().to_path_buf();
+    let frames_cloned = frames.clone();
 
+    let frames_len = frames.len();
     let concat_path = temp_dir_path.join("concat.txt");
     let concat_path_cloned = concat_path.clone();
     tokio::task::spawn_blocking(move || -> std::result::Result<(), &'static str> {
@@ -171,9 +431,28 @@ async fn convert_ugoira(
     let output = tempfile::NamedTempFile::new().map_err(|_| "Failed to create output temp file")?;
     let output_path = output.path().to_path_buf();
 
-    warn!("{}", concat_path.display());
-    warn!("{}", std::fs::read_to_string(&concat_path).unwrap_or_else(|_| "Failed to read concat file".into()));
-    let result = tokio::process::Command::new("ffmpeg")
+    let Some((ffmpeg, ffmpeg_args)) = ffmpeg else {
+        let frames_dir = temp_dir.path().to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            encode_gif(&frames_dir, &frames_cloned, &output_path, max_pixels)
+        })
+        .await
+        .map_err(|_| "Blocking task panicked")??;
+        return Ok(output.into_temp_path());
+    };
+
+    trace!("[ugoira] Encoding {} frames from {}", frames_len, concat_path.display());
+    let encoder_args = if ffmpeg_args.is_empty() {
+        format
+            .ffmpeg_args()
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect()
+    } else {
+        ffmpeg_args.clone()
+    };
+
+    let result = tokio::process::Command::new(ffmpeg)
         .args([
             "-y",
             "-f",
@@ -182,16 +461,11 @@ async fn convert_ugoira(
             "0",
             "-i",
             concat_path.to_str().ok_or("Invalid concat path")?,
-            "-c:v",
-            "libvpx-vp9",
-            "-b:v",
-            "0",
-            "-pix_fmt",
-            "yuv420p",
+        ])
+        .args(encoder_args)
+        .args([
             "-loglevel",
             "error",
-            "-f",
-            "webm",
             output_path.to_str().ok_or("Invalid output path")?,
         ])
         .output()
@@ -207,17 +481,140 @@ async fn convert_ugoira(
     Ok(output.into_temp_path())
 }
 
-fn resize(path: TempPath, width: u32, height: u32) -> std::result::Result<TempPath, &'static str> {
-    let src_image = ImageReader::open(&path)
+async fn transcode(
+    path: TempPath,
+    format: TranscodeFormat,
+    quality: u8,
+    ffmpeg: Option<&PathBuf>,
+) -> std::result::Result<TempPath, &'static str> {
+    let ffmpeg = ffmpeg.ok_or("ffmpeg is required to transcode images")?;
+    let output = tempfile::NamedTempFile::new().map_err(|_| "Failed to create output temp file")?;
+
+    let result = tokio::process::Command::new(ffmpeg)
+        .args(["-y", "-i", path.to_str().ok_or("Invalid input path")?])
+        .args(format.ffmpeg_args(quality))
+        .args([
+            "-loglevel",
+            "error",
+            output.path().to_str().ok_or("Invalid output path")?,
+        ])
+        .output()
+        .await
+        .map_err(|_| "Failed to spawn ffmpeg (is ffmpeg installed?)")?;
+
+    if !result.status.success() {
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        error!("[transcode] ffmpeg failed: {stderr}");
+        return Err("ffmpeg transcoding failed");
+    }
+
+    Ok(output.into_temp_path())
+}
+
+/// Internal encoder used when ffmpeg is unavailable
+fn encode_gif(
+    dir: &Path,
+    frames: &[PixivUgoiraFrame],
+    output: &Path,
+    max_pixels: u64,
+) -> std::result::Result<(), &'static str> {
+    let file = std::fs::File::create(output).map_err(|_| "Failed to create gif file")?;
+    let mut encoder = GifEncoder::new(file);
+    encoder
+        .set_repeat(Repeat::Infinite)
+        .map_err(|_| "Failed to set gif repeat")?;
+
+    for frame in frames {
+        let mut reader = ImageReader::open(dir.join(&frame.file))
+            .and_then(|reader| reader.with_guessed_format())
+            .map_err(|_| "Failed to open ugoira frame")?;
+        reader.limits(decode_limits(max_pixels));
+        let image = match reader.decode() {
+            Ok(image) => image.to_rgba8(),
+            Err(ImageError::Limits(e)) => {
+                warn!("Ugoira frame too large to decode: {e}");
+                return Err("Ugoira frame too large to decode");
+            }
+            Err(_) => return Err("Failed to decode ugoira frame"),
+        };
+        let delay = Delay::from_numer_denom_ms(frame.delay, 1);
+        encoder
+            .encode_frame(Frame::from_parts(image, 0, 0, delay))
+            .map_err(|_| "Failed to encode gif frame")?;
+    }
+    Ok(())
+}
+
+/// Check that a downloaded image or ugoira zip can be read
+fn validate(path: &TempPath, request: &ArchiveRequest) -> std::result::Result<(), &'static str> {
+    match request {
+        ArchiveRequest::Ugoira { .. } | ArchiveRequest::UgoiraPoster { .. } => {
+            let zip_file = std::fs::File::open(path).map_err(|_| "Failed to open ugoira zip")?;
+            zip::ZipArchive::new(zip_file).map_err(|_| "Failed to parse ugoira zip")?;
+        }
+        ArchiveRequest::Original(url) if url.ends_with(".zip") => {
+            let zip_file = std::fs::File::open(path).map_err(|_| "Failed to open ugoira zip")?;
+            zip::ZipArchive::new(zip_file).map_err(|_| "Failed to parse ugoira zip")?;
+        }
+        _ => {
+            ImageReader::open(path)
+                .and_then(|reader| reader.with_guessed_format())
+                .map_err(|_| "Failed to open image")?
+                .into_dimensions()
+                .map_err(|_| "Failed to read image")?;
+        }
+    }
+    Ok(())
+}
+
+async fn extract_ugoira_frame(
+    zip_path: TempPath,
+    frame: String,
+) -> std::result::Result<TempPath, &'static str> {
+    tokio::task::spawn_blocking(move || -> std::result::Result<TempPath, &'static str> {
+        let zip_file = std::fs::File::open(&zip_path).map_err(|_| "Failed to open ugoira zip")?;
+        let mut archive =
+            zip::ZipArchive::new(zip_file).map_err(|_| "Failed to parse ugoira zip")?;
+        let mut entry = archive
+            .by_name(&frame)
+            .map_err(|_| "Ugoira poster frame not found")?;
+
+        let mut output =
+            tempfile::NamedTempFile::new().map_err(|_| "Failed to create poster temp file")?;
+        std::io::copy(&mut entry, &mut output).map_err(|_| "Failed to extract poster frame")?;
+        Ok(output.into_temp_path())
+    })
+    .await
+    .map_err(|_| "Blocking task panicked")?
+}
+
+fn resize(
+    path: TempPath,
+    width: u32,
+    height: u32,
+    max_pixels: u64,
+) -> std::result::Result<TempPath, &'static str> {
+    // temp files have no extension to tell the format
+    let mut reader = ImageReader::open(&path)
+        .and_then(|reader| reader.with_guessed_format())
         .map_err(|e| {
             warn!("Failed to open image: {e}");
             "Failed to open image"
-        })?
-        .decode()
-        .map_err(|e| {
-            warn!("Failed to decode image: {e}");
-            "Failed to decode image"
         })?;
+    reader.limits(decode_limits(max_pixels));
+    let format = reader.format().ok_or("Unknown image format")?;
+
+    let src_image = match reader.decode() {
+        Ok(image) => image,
+        Err(ImageError::Limits(e)) => {
+            warn!("Image too large to decode, keeping it as is: {e}");
+            return Ok(path);
+        }
+        Err(e) => {
+            warn!("Failed to decode image: {e}");
+            return Err("Failed to decode image");
+        }
+    };
 
     if src_image.width() != width || src_image.height() != height {
         let mut dst_image = DynamicImage::new(width, height, src_image.color());
@@ -234,10 +631,100 @@ fn resize(path: TempPath, width: u32, height: u32) -> std::result::Result<TempPa
                 "Failed to resize image"
             })?;
 
-        dst_image.save(&path).map_err(|e| {
+        dst_image.save_with_format(&path, format).map_err(|e| {
             warn!("Failed to save resized image: {e}");
             "Failed to save resized image"
         })?;
     }
     Ok(path)
 }
+
+fn thumbnail(
+    path: TempPath,
+    size: u32,
+    max_pixels: u64,
+) -> std::result::Result<TempPath, &'static str> {
+    let mut reader = ImageReader::open(&path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|_| "Failed to open image")?;
+    reader.limits(decode_limits(max_pixels));
+    let image = match reader.decode() {
+        Ok(image) => image,
+        Err(ImageError::Limits(e)) => {
+            warn!("Image too large to decode, keeping it as its thumbnail: {e}");
+            return Ok(path);
+        }
+        Err(e) => {
+            warn!("Failed to decode image for thumbnail: {e}");
+            return Err("Failed to decode image");
+        }
+    };
+
+    let image = match image.width() > size || image.height() > size {
+        true => image.thumbnail(size, size),
+        false => image,
+    };
+
+    let mut output =
+        tempfile::NamedTempFile::new().map_err(|_| "Failed to create thumbnail temp file")?;
+    // jpeg has no alpha channel
+    DynamicImage::ImageRgb8(image.to_rgb8())
+        .write_to(&mut output, ImageFormat::Jpeg)
+        .map_err(|e| {
+            warn!("Failed to encode thumbnail: {e}");
+            "Failed to encode thumbnail"
+        })?;
+    Ok(output.into_temp_path())
+}
+
+fn decode_limits(max_pixels: u64) -> Limits {
+    let mut limits = Limits::default();
+    // RGBA8 for the decoded buffer
+    limits.max_alloc = Some(max_pixels.saturating_mul(4));
+    limits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 64x64 png, 16 KiB once decoded to RGBA8
+    fn png() -> TempPath {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        DynamicImage::new_rgb8(64, 64)
+            .write_to(&mut file, ImageFormat::Png)
+            .unwrap();
+        file.into_temp_path()
+    }
+
+    #[test]
+    fn keep_images_over_the_limit() {
+        let path = png();
+        let original = std::fs::read(&path).unwrap();
+        let path = resize(path, 32, 32, 1024).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+
+        let path = thumbnail(path, 16, 1024).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+    }
+
+    #[test]
+    fn decode_images_under_the_limit() {
+        let path = resize(png(), 32, 32, 64 * 64).unwrap();
+        let reader = ImageReader::open(&path).unwrap().with_guessed_format().unwrap();
+        assert_eq!(reader.into_dimensions().unwrap(), (32, 32));
+    }
+
+    #[test]
+    fn limit_ugoira_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::copy(png(), dir.path().join("000000.png")).unwrap();
+        let frames = [PixivUgoiraFrame {
+            file: "000000.png".to_string(),
+            delay: 100,
+        }];
+        let output = dir.path().join("ugoira.gif");
+        assert!(encode_gif(dir.path(), &frames, &output, 1024).is_err());
+        assert!(encode_gif(dir.path(), &frames, &output, 64 * 64).is_ok());
+    }
+}
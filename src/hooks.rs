@@ -0,0 +1,48 @@
+use std::{fmt, path::Path, sync::Arc};
+
+use crate::artwork::PixivArtwork;
+
+/// Called with the source url, the work and the folder of its post
+pub type ArchivedHook = dyn Fn(&str, &PixivArtwork, &Path) + Send + Sync;
+/// Called with the source url and the kind of the error, as recorded in `failed.json`
+pub type FailedHook = dyn Fn(&str, &str) + Send + Sync;
+/// Called with the stage (`user`, `series`, `artwork` or `files`), the done and the total items
+pub type ProgressHook = dyn Fn(&str, u64, u64) + Send + Sync;
+
+/// Callbacks of a program embedding the archiver, see [`crate::Archiver`]
+#[derive(Clone, Default)]
+pub struct Hooks {
+    pub archived: Option<Arc<ArchivedHook>>,
+    pub failed: Option<Arc<FailedHook>>,
+    pub progress: Option<Arc<ProgressHook>>,
+}
+
+impl Hooks {
+    pub fn archived(&self, source: &str, artwork: &PixivArtwork, dir: &Path) {
+        if let Some(hook) = &self.archived {
+            hook(source, artwork, dir);
+        }
+    }
+
+    pub fn failed(&self, source: &str, kind: &str) {
+        if let Some(hook) = &self.failed {
+            hook(source, kind);
+        }
+    }
+
+    pub fn progress(&self, stage: &str, done: u64, total: u64) {
+        if let Some(hook) = &self.progress {
+            hook(stage, done, total);
+        }
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("archived", &self.archived.is_some())
+            .field("failed", &self.failed.is_some())
+            .field("progress", &self.progress.is_some())
+            .finish()
+    }
+}
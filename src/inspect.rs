@@ -0,0 +1,154 @@
+use std::process::exit;
+
+use log::{error, info, warn};
+use post_archiver::{PostId, manager::PostArchiverManager};
+use post_archiver_utils::{display_metadata, get_post_path};
+use sha2::{Digest, Sha256};
+
+use crate::config::{InspectArgs, VerifyArgs};
+
+/// Open the database of an existing archive, without creating one or writing to it
+pub fn open(args: &InspectArgs) -> PostArchiverManager {
+    let manager = match PostArchiverManager::open(&args.output) {
+        Ok(Some(manager)) => manager,
+        Ok(None) => {
+            error!("[inspect] No archive at {}", args.output.display());
+            exit(1);
+        }
+        Err(e) => {
+            error!("[inspect] Failed to open {}: {e:?}", args.output.display());
+            exit(1);
+        }
+    };
+
+    if let Err(e) = manager.conn().execute_batch("PRAGMA query_only = ON") {
+        error!("[inspect] Failed to open {} read-only: {e}", args.output.display());
+        exit(1);
+    }
+    manager
+}
+
+/// Check that every file of the archive exists and is not empty,
+/// and with `--hashes` that it still matches its stored SHA-256
+pub fn verify(args: &VerifyArgs) {
+    let VerifyArgs { inspect: args, hashes } = args;
+    let manager = open(args);
+    let mut stmt = manager
+        .conn()
+        .prepare("SELECT post, filename, json_extract(extra, '$.sha256') FROM file_metas")
+        .unwrap();
+    let files = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, PostId>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })
+        .unwrap();
+
+    let (mut total, mut broken, mut unhashed) = (0, 0, 0);
+    for file in files {
+        let (post, filename, sha256) = file.unwrap();
+        let path = get_post_path(&args.output, post).join(&filename);
+        total += 1;
+
+        match path.metadata() {
+            Ok(metadata) if metadata.len() > 0 => {}
+            Ok(_) => {
+                warn!("[verify] Empty file {}", path.display());
+                broken += 1;
+                continue;
+            }
+            Err(_) => {
+                warn!("[verify] Missing file {}", path.display());
+                broken += 1;
+                continue;
+            }
+        }
+
+        if !hashes {
+            continue;
+        }
+        let Some(sha256) = sha256 else {
+            unhashed += 1;
+            continue;
+        };
+        match hash_file(&path) {
+            Ok(actual) if actual == sha256 => {}
+            Ok(_) => {
+                warn!("[verify] Corrupted file {}", path.display());
+                broken += 1;
+            }
+            Err(e) => {
+                warn!("[verify] Failed to read {}: {e}", path.display());
+                broken += 1;
+            }
+        }
+    }
+
+    if unhashed > 0 {
+        info!("[verify] {unhashed} files were archived without a hash, skipped comparing them");
+    }
+    info!("[verify] Checked {total} files, {broken} missing, empty or corrupted");
+    if broken > 0 {
+        exit(1);
+    }
+}
+
+/// SHA-256 of the file, as lowercase hex
+fn hash_file(path: &std::path::Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Count the rows of the archive
+pub fn stats(args: &InspectArgs) {
+    let manager = open(args);
+    let count = |table: &str| {
+        manager
+            .conn()
+            .query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+                row.get::<_, u64>(0)
+            })
+            .map(|count| count.to_string())
+            .unwrap_or_else(|e| format!("? ({e})"))
+    };
+
+    display_metadata(
+        "Pixiv Archive Stats",
+        &[
+            ("Output", args.output.to_str().unwrap()),
+            ("Posts", &count("posts")),
+            ("Authors", &count("authors")),
+            ("Files", &count("file_metas")),
+            ("Tags", &count("tags")),
+            ("Collections", &count("collections")),
+        ],
+    );
+}
+
+/// Print id, source and title of the archived posts
+pub fn list(args: &InspectArgs) {
+    let manager = open(args);
+    let mut stmt = manager
+        .conn()
+        .prepare("SELECT id, source, title FROM posts ORDER BY id")
+        .unwrap();
+    let posts = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, u64>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })
+        .unwrap();
+
+    for post in posts {
+        let (id, source, title) = post.unwrap();
+        println!("{id}\t{}\t{title}", source.unwrap_or_default());
+    }
+}
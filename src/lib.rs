@@ -0,0 +1,375 @@
+//! Archive pixiv works into a [PostArchiver](https://github.com/xiao-e-yun/PostArchiver) folder.
+//!
+//! The `pixiv-archive` binary is a thin command line over [`Archiver`], which other programs
+//! can embed with [`Archiver::builder`], or with their own [`Config`] and hooks.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use api::PixivClient;
+use artwork::{PixivArtwork, PixivArtworkId, archive_artworks, resolve_artworks};
+use config::{Config, ConfigError, PixivTarget, parse_target};
+use deleted::DeletedWorks;
+use failed::FailedWorks;
+use favorite::reslove_current_user;
+use file::{ArchiveRequest, download_files};
+use log::{info, warn};
+use plyne::{Input, define_tasks};
+use post_archiver::{
+    Comment,
+    importer::{UnsyncContent, UnsyncFileMeta},
+};
+use ranking::reslove_ranking;
+use search::reslove_search;
+use series::{PixivSeriesId, reslove_series};
+use storage::Storage;
+use tempfile::TempPath;
+use throttle::Throttle;
+use tokio::sync::Mutex;
+use user::{PixivUserId, reslove_users, resolve_vanity};
+
+pub mod api;
+pub mod artwork;
+pub mod blocklist;
+pub mod checkpoint;
+pub mod comment;
+pub mod config;
+pub mod cookies;
+pub mod data_export;
+pub mod deleted;
+pub mod disk;
+pub mod dns;
+pub mod error;
+pub mod export;
+pub mod failed;
+pub mod favorite;
+pub mod file;
+pub mod hooks;
+pub mod inspect;
+pub mod novel_format;
+pub mod plain;
+pub mod proxy;
+pub mod prune;
+pub mod ranking;
+pub mod related;
+pub mod remote;
+pub mod search;
+pub mod series;
+pub mod shutdown;
+pub mod storage;
+pub mod tag;
+pub mod throttle;
+pub mod url_map;
+pub mod user;
+
+/// Archives the targets of a [`Config`] into its output folder.
+///
+/// Progress bars are drawn on `config.multi`, give it a hidden draw target to report
+/// progress by the hooks only.
+#[derive(Clone)]
+pub struct Archiver {
+    config: Config,
+    client: PixivClient,
+    storage: Option<Arc<OpenStorage>>,
+}
+
+/// Opens the [`Storage`] of each run, see [`Archiver::with_storage`]
+pub type OpenStorage = dyn Fn(&Config) -> post_archiver_utils::Result<Box<dyn Storage + Send>>
+    + Send
+    + Sync;
+
+impl std::fmt::Debug for Archiver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Archiver")
+            .field("config", &self.config)
+            .field("client", &self.client)
+            .field("storage", &self.storage.as_ref().map(|_| "custom"))
+            .finish()
+    }
+}
+
+impl Archiver {
+    /// Open the state files of the output folder and create the client,
+    /// the config must be prepared (see [`Config::prepare`])
+    pub fn new(mut config: Config) -> Self {
+        config.failed = FailedWorks::open(&config.output);
+        config.deleted = DeletedWorks::open(&config.output);
+        config.throttle = Throttle::new(config.multi.clone(), config.adaptive_limit, config.limit);
+
+        let client = PixivClient::new(&config);
+        Self {
+            config,
+            client,
+            storage: None,
+        }
+    }
+
+    /// Write the archived posts to another backend than the one of `--layout`,
+    /// `open` is called at the start of every run
+    pub fn with_storage(
+        mut self,
+        open: impl Fn(&Config) -> post_archiver_utils::Result<Box<dyn Storage + Send>>
+        + Send
+        + Sync
+        + 'static,
+    ) -> Self {
+        self.storage = Some(Arc::new(open));
+        self
+    }
+
+    /// Called after every archived work
+    pub fn on_archived(
+        mut self,
+        hook: impl Fn(&str, &PixivArtwork, &Path) + Send + Sync + 'static,
+    ) -> Self {
+        self.config.hooks.archived = Some(Arc::new(hook));
+        self
+    }
+
+    /// Called for every work which failed to archive
+    pub fn on_failed(mut self, hook: impl Fn(&str, &str) + Send + Sync + 'static) -> Self {
+        self.config.hooks.failed = Some(Arc::new(hook));
+        self
+    }
+
+    /// Called when the items of a stage grow or get done, see [`hooks::ProgressHook`]
+    pub fn on_progress(mut self, hook: impl Fn(&str, u64, u64) + Send + Sync + 'static) -> Self {
+        self.config.hooks.progress = Some(Arc::new(hook));
+        self
+    }
+
+    /// Start from the defaults of the command line, archiving into `output`
+    pub fn builder(session: impl Into<String>, output: impl Into<PathBuf>) -> ArchiverBuilder {
+        let mut config = Config::default();
+        config.session = session.into();
+        config.output = output.into();
+        ArchiverBuilder {
+            config,
+            storage: None,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn config_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    pub fn client(&self) -> &PixivClient {
+        &self.client
+    }
+
+    /// Replace the session (e.g. refreshed from the browser), used by the next run
+    pub fn set_session(&mut self, session: String) {
+        self.config.session = session;
+        self.client = PixivClient::new(&self.config);
+    }
+
+    /// Archive every target of the config once
+    pub async fn run(&self) -> post_archiver_utils::Result<()> {
+        let manager = match &self.storage {
+            Some(open) => open(&self.config)?,
+            None => storage::open(&self.config)?,
+        };
+
+        PixivSystem::new(Mutex::new(manager), self.config.clone(), self.client.clone())
+            .execute()
+            .await;
+        Ok(())
+    }
+}
+
+/// Builds an [`Archiver`] from the defaults of the command line, see [`Archiver::builder`]
+pub struct ArchiverBuilder {
+    config: Config,
+    storage: Option<Arc<OpenStorage>>,
+}
+
+impl std::fmt::Debug for ArchiverBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ArchiverBuilder")
+            .field("config", &self.config)
+            .field("storage", &self.storage.as_ref().map(|_| "custom"))
+            .finish()
+    }
+}
+
+impl ArchiverBuilder {
+    /// Archive a user, work or series by its url, like the urls on the command line
+    pub fn target(mut self, url: &str) -> Result<Self, String> {
+        self.config.targets.push(parse_target(url)?);
+        Ok(self)
+    }
+
+    /// Change any other option
+    pub fn configure(mut self, configure: impl FnOnce(&mut Config)) -> Self {
+        configure(&mut self.config);
+        self
+    }
+
+    /// See [`Archiver::with_storage`]
+    pub fn with_storage(
+        mut self,
+        open: impl Fn(&Config) -> post_archiver_utils::Result<Box<dyn Storage + Send>>
+        + Send
+        + Sync
+        + 'static,
+    ) -> Self {
+        self.storage = Some(Arc::new(open));
+        self
+    }
+
+    /// See [`Archiver::on_archived`]
+    pub fn on_archived(
+        mut self,
+        hook: impl Fn(&str, &PixivArtwork, &Path) + Send + Sync + 'static,
+    ) -> Self {
+        self.config.hooks.archived = Some(Arc::new(hook));
+        self
+    }
+
+    /// See [`Archiver::on_failed`]
+    pub fn on_failed(mut self, hook: impl Fn(&str, &str) + Send + Sync + 'static) -> Self {
+        self.config.hooks.failed = Some(Arc::new(hook));
+        self
+    }
+
+    /// See [`Archiver::on_progress`]
+    pub fn on_progress(mut self, hook: impl Fn(&str, u64, u64) + Send + Sync + 'static) -> Self {
+        self.config.hooks.progress = Some(Arc::new(hook));
+        self
+    }
+
+    /// Prepare the config (see [`Config::prepare`]) and create the archiver
+    pub fn build(mut self) -> Result<Archiver, ConfigError> {
+        self.config.prepare()?;
+        let mut archiver = Archiver::new(self.config);
+        archiver.storage = self.storage;
+        Ok(archiver)
+    }
+}
+
+pub type Manager = Mutex<Box<dyn Storage + Send>>;
+
+pub type FileEvent = (
+    Vec<ArchiveRequest>,
+    tokio::sync::oneshot::Sender<HashMap<String, TempPath>>,
+);
+
+#[derive(Debug)]
+pub struct SyncEvent {
+    source: String,
+    artwork: PixivArtwork,
+    contents: Vec<UnsyncContent<ArchiveRequest>>,
+    thumb: Option<UnsyncFileMeta<ArchiveRequest>>,
+    comments: Vec<Comment>,
+    files: tokio::sync::oneshot::Receiver<HashMap<String, TempPath>>,
+}
+
+define_tasks! {
+    PixivSystem
+    pipelines {
+        users_pipeline: PixivUserId,
+        series_pipeline: PixivSeriesId,
+        artworks_pipeline: PixivArtworkId,
+        files_pipeline: FileEvent,
+        sync_pipeline: SyncEvent,
+    }
+    vars {
+        manager: Manager,
+        config: Config,
+        client: PixivClient,
+    }
+    tasks {
+        resolve_main,
+        reslove_current_user,
+        reslove_users,
+        reslove_series,
+        reslove_search,
+        reslove_ranking,
+        resolve_artworks,
+        archive_artworks,
+        download_files,
+    }
+}
+
+async fn resolve_main(
+    users_pipeline: Input<PixivUserId>,
+    series_pipeline: Input<PixivSeriesId>,
+    artworks_pipeline: Input<PixivArtworkId>,
+    config: &Config,
+    client: &PixivClient,
+) {
+    for user in &config.users {
+        info!("[main] Archive user: {user:?}");
+        users_pipeline.send(*user).unwrap();
+    }
+
+    macro_rules! remap {
+        ($series: expr, $fn: expr) => {
+            $series.iter().cloned().map($fn)
+        };
+    }
+
+    for illust_series in remap!(config.illust_series, PixivSeriesId::Illust) {
+        info!("[main] Archive Illust Series: {illust_series:?}");
+        series_pipeline.send(illust_series).unwrap();
+    }
+    for novel_series in remap!(config.novel_series, PixivSeriesId::Novel) {
+        info!("[main] Archive Novel Series: {novel_series:?}");
+        series_pipeline.send(novel_series).unwrap();
+    }
+
+    for illusts in remap!(config.illusts, PixivArtworkId::Illust) {
+        info!("[main] Archive Illusts: {illusts:?}");
+        artworks_pipeline.send(illusts).unwrap();
+    }
+    for novels in remap!(config.novels, PixivArtworkId::Novel) {
+        info!("[main]   Novel Series: {novels:?}");
+        artworks_pipeline.send(novels).unwrap();
+    }
+
+    for unlisted in remap!(config.unlisted_illusts, PixivArtworkId::UnlistedIllust) {
+        info!("[main] Archive Unlisted Illusts: {unlisted:?}");
+        artworks_pipeline.send(unlisted).unwrap();
+    }
+    for unlisted in remap!(config.unlisted_novels, PixivArtworkId::UnlistedNovel) {
+        info!("[main] Archive Unlisted Novels: {unlisted:?}");
+        artworks_pipeline.send(unlisted).unwrap();
+    }
+
+    let resumed = config.checkpoint.pending().into_iter().filter_map(|url| {
+        parse_target(&url)
+            .inspect_err(|e| warn!("[main] Skipping checkpoint entry: {e}"))
+            .ok()
+    });
+    let failed = match config.retry_failed {
+        true => config.failed.urls(),
+        false => vec![],
+    };
+    let failed = failed.into_iter().filter_map(|url| {
+        parse_target(&url)
+            .inspect_err(|e| warn!("[main] Skipping failed entry: {e}"))
+            .ok()
+    });
+    for target in config.targets.iter().cloned().chain(resumed).chain(failed) {
+        info!("[main] Archive {target:?}");
+        match target {
+            PixivTarget::User(user) => users_pipeline.send(user).unwrap(),
+            PixivTarget::Vanity(name) => {
+                if let Some(user) = resolve_vanity(client, &name).await {
+                    info!("[main] Resolved pixiv.me/{name} to user {user}");
+                    users_pipeline.send(user).unwrap();
+                }
+            }
+            PixivTarget::Artwork(artwork) => artworks_pipeline.send(artwork).unwrap(),
+            PixivTarget::Series(series) => series_pipeline.send(series).unwrap(),
+        }
+    }
+}
+
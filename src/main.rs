@@ -1,36 +1,31 @@
-use std::collections::HashMap;
-
-use api::PixivClient;
-use artwork::{PixivArtwork, PixivArtworkId, archive_artworks, resolve_artworks};
-use config::Config;
-use favorite::reslove_current_user;
-use file::{ArchiveRequest, download_files};
-use log::{info, warn};
-use plyne::{Input, define_tasks};
-use post_archiver::{
-    Comment,
-    importer::{UnsyncContent, UnsyncFileMeta},
-    manager::PostArchiverManager,
+use log::{error, info, warn};
+use pixiv_archive::{
+    Archiver,
+    checkpoint::Checkpoint,
+    config::{Cli, Command},
+    cookies::load_session,
+    disk,
+    error::PixivError,
+    export,
+    favorite::fetch_current_user,
+    inspect,
 };
 use post_archiver_utils::display_metadata;
-use series::{PixivSeriesId, reslove_series};
-use tempfile::TempPath;
-use tokio::sync::Mutex;
-use user::{PixivUserId, reslove_users};
-
-pub mod api;
-pub mod artwork;
-pub mod comment;
-pub mod config;
-pub mod favorite;
-pub mod file;
-pub mod series;
-pub mod tag;
-pub mod user;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    let config = Config::init();
+    let mut config = match Cli::init() {
+        Command::Archive(config) => config,
+        Command::Verify(args) => return inspect::verify(&args),
+        Command::Stats(args) => return inspect::stats(&args),
+        Command::List(args) => return inspect::list(&args),
+        Command::Export(args) => return export::export(&args),
+    };
+
+    if config.print_config {
+        println!("{:#?}", config.redacted());
+        return;
+    }
 
     fn yes_or_no(value: bool) -> &'static str {
         if value { "Yes" } else { "No" }
@@ -43,25 +38,52 @@ async fn main() {
                 format!("v{}", env!("CARGO_PKG_VERSION")).as_str(),
             ),
             ("Overwrite", yes_or_no(config.overwrite)),
+            ("Update", yes_or_no(config.update)),
+            (
+                "Watch",
+                &config
+                    .watch
+                    .map(|interval| format!("every {}s", interval.as_secs()))
+                    .unwrap_or("No".to_string()),
+            ),
             ("Output", config.output.to_str().unwrap()),
             ("Limit", &config.limit.to_string()),
+            ("Urls", &config.targets.len().to_string()),
             ("Users", &config.users.len().to_string()),
             ("Illusts", &config.illusts.len().to_string()),
             ("Novels", &config.novels.len().to_string()),
+            (
+                "Unlisted",
+                &(config.unlisted_illusts.len() + config.unlisted_novels.len()).to_string(),
+            ),
             ("Illust Series", &config.illust_series.len().to_string()),
             ("Novel Series", &config.novel_series.len().to_string()),
             ("Followed Users", yes_or_no(config.followed_users)),
+            ("Following Feed", yes_or_no(config.following_feed.is_some())),
             ("Favorite", yes_or_no(config.favorite)),
+            ("Search", &config.search.len().to_string()),
+            (
+                "Ranking",
+                config.ranking.map(|mode| mode.param()).unwrap_or("None"),
+            ),
         ],
     );
 
-    if config.users.is_empty()
+    if config.targets.is_empty()
+        && config.users.is_empty()
         && config.illusts.is_empty()
         && config.novels.is_empty()
+        && config.unlisted_illusts.is_empty()
+        && config.unlisted_novels.is_empty()
         && config.illust_series.is_empty()
         && config.novel_series.is_empty()
         && !config.followed_users
         && !config.favorite
+        && config.following_feed.is_none()
+        && config.search.is_empty()
+        && config.ranking.is_none()
+        && !config.resume
+        && !config.retry_failed
     {
         warn!("[main] No targets specified.");
         warn!("[main] Run with --help for more information.");
@@ -73,97 +95,118 @@ async fn main() {
         std::fs::create_dir_all(&config.output).unwrap();
     }
 
-    if !config.has_ffmpeg {
-        warn!("[main] ffmpeg not found — Ugoira artworks will be skipped");
-    }
-
-    info!("[main] Connecting to PostArchiver");
-    let manager = PostArchiverManager::open_or_create(&config.output).unwrap();
-
-    let client = PixivClient::new(&config);
-
-    PixivSystem::new(Mutex::new(manager), config, client)
-        .execute()
-        .await;
-
-    info!("[main] Archive completed");
-}
-
-pub type Manager = Mutex<PostArchiverManager>;
-
-pub type FileEvent = (
-    Vec<ArchiveRequest>,
-    tokio::sync::oneshot::Sender<HashMap<String, TempPath>>,
-);
-
-#[derive(Debug)]
-pub struct SyncEvent {
-    source: String,
-    artwork: PixivArtwork,
-    contents: Vec<UnsyncContent<ArchiveRequest>>,
-    thumb: Option<UnsyncFileMeta<ArchiveRequest>>,
-    comments: Vec<Comment>,
-    files: tokio::sync::oneshot::Receiver<HashMap<String, TempPath>>,
-}
-
-define_tasks! {
-    PixivSystem
-    pipelines {
-        users_pipeline: PixivUserId,
-        series_pipeline: PixivSeriesId,
-        artworks_pipeline: PixivArtworkId,
-        files_pipeline: FileEvent,
-        sync_pipeline: SyncEvent,
-    }
-    vars {
-        manager: Manager,
-        config: Config,
-        client: PixivClient,
-    }
-    tasks {
-        resolve_main,
-        reslove_current_user,
-        reslove_users,
-        reslove_series,
-        resolve_artworks,
-        archive_artworks,
-        download_files,
+    if let Some(free) = disk::free_space(&config.output)
+        && free < config.min_free_space
+    {
+        error!(
+            "[main] Only {} free on the output disk, below --min-free-space ({})",
+            disk::format_size(free),
+            disk::format_size(config.min_free_space)
+        );
+        std::process::exit(1);
     }
-}
 
-async fn resolve_main(
-    users_pipeline: Input<PixivUserId>,
-    series_pipeline: Input<PixivSeriesId>,
-    artworks_pipeline: Input<PixivArtworkId>,
-    config: &Config,
-) {
-    for user in &config.users {
-        info!("[main] Archive user: {user:?}");
-        users_pipeline.send(*user).unwrap();
+    if let Some(temp_dir) = &config.temp_dir {
+        std::fs::create_dir_all(temp_dir).unwrap();
+        if tempfile::env::override_temp_dir(temp_dir).is_err() {
+            warn!("[main] Temp dir was already set, ignoring --temp-dir");
+        }
     }
 
-    macro_rules! remap {
-        ($series: expr, $fn: expr) => {
-            $series.iter().cloned().map($fn)
-        };
+    if !config.has_ffmpeg {
+        warn!("[main] ffmpeg not found — Ugoira artworks will be encoded as GIF");
+        if config.transcode.is_some() {
+            warn!("[main] ffmpeg not found — images will not be transcoded");
+        }
     }
 
-    for illust_series in remap!(config.illust_series, PixivSeriesId::Illust) {
-        info!("[main] Archive Illust Series: {illust_series:?}");
-        series_pipeline.send(illust_series).unwrap();
-    }
-    for novel_series in remap!(config.novel_series, PixivSeriesId::Novel) {
-        info!("[main] Archive Novel Series: {novel_series:?}");
-        series_pipeline.send(novel_series).unwrap();
+    config.checkpoint = match config.resume {
+        true => match Checkpoint::load(&config.output) {
+            Ok(checkpoint) => checkpoint,
+            Err(e) => {
+                error!("[main] Failed to load checkpoint: {e}");
+                return;
+            }
+        },
+        false => {
+            let checkpoint = Checkpoint::new(&config.output);
+            if checkpoint.path().exists() {
+                warn!("[main] Overwriting the checkpoint of an unfinished run, use --resume to continue it");
+            }
+            checkpoint
+        }
+    };
+
+    let mut archiver = Archiver::new(config);
+    match fetch_current_user(archiver.client()).await {
+        Ok(user) => info!("[main] Logged in as user {user}"),
+        Err(PixivError::Auth) if archiver.config().anonymous => {
+            warn!("[main] Session expired, continuing anonymously with public works only");
+            let config = archiver.config_mut();
+            config.favorite = false;
+            config.followed_users = false;
+            config.following_feed = None;
+        }
+        Err(PixivError::Auth) => {
+            error!("[main] Session expired, refresh the PHPSESSID cookie from your browser and run again");
+            error!("[main] Or run with --anonymous to archive public works only");
+            std::process::exit(1);
+        }
+        Err(e) => warn!("[main] Failed to check the session: {e}"),
     }
 
-    for illusts in remap!(config.illusts, PixivArtworkId::Illust) {
-        info!("[main] Archive Illusts: {illusts:?}");
-        artworks_pipeline.send(illusts).unwrap();
-    }
-    for novels in remap!(config.novels, PixivArtworkId::Novel) {
-        info!("[main]   Novel Series: {novels:?}");
-        artworks_pipeline.send(novels).unwrap();
+    archiver.config().shutdown.listen();
+    loop {
+        // the session may expire between the runs of --watch, the browser has the fresh one
+        if let Some(browser) = archiver.config().cookies_from_browser {
+            match load_session(browser) {
+                Ok(session) => archiver.set_session(session),
+                Err(e) => warn!("[main] Failed to read the session from {browser:?}: {e}"),
+            }
+        }
+
+        let config = archiver.config();
+        if let Err(e) = archiver.run().await {
+            error!("[main] Failed to open the storage: {e}");
+            std::process::exit(1);
+        }
+
+        if config.shutdown.is_requested() {
+            match config.checkpoint.save() {
+                Ok(()) => info!(
+                    "[main] Archive interrupted, run with --resume to continue ({})",
+                    config.checkpoint.path().display()
+                ),
+                Err(e) => error!("[main] Archive interrupted, failed to save checkpoint: {e}"),
+            }
+            break;
+        }
+        let deleted = config.deleted.take_recent();
+        if !deleted.is_empty() {
+            warn!("[main] {} works were deleted or restricted since the last run:", deleted.len());
+            for (url, tombstone) in deleted {
+                let archived = match tombstone.archived {
+                    Some(true) => ", archived",
+                    Some(false) => ", not archived",
+                    None => "",
+                };
+                warn!("[main]   {url} ({}{archived})", tombstone.reason);
+            }
+        }
+
+        if let Err(e) = config.checkpoint.clear() {
+            warn!("[main] Failed to remove checkpoint: {e}");
+        }
+        info!("[main] Archive completed");
+
+        let Some(interval) = config.watch else {
+            break;
+        };
+        info!("[main] Watching, next run in {}s", interval.as_secs());
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {},
+            _ = config.shutdown.wait() => break,
+        }
     }
 }
 
@@ -0,0 +1,83 @@
+/// Convert `[newpage]`, `[chapter:]`, `[jump:]`, `[[rb:]]` and `[[jumpuri:]]` into Markdown,
+/// ruby is kept as inline html. Unknown markers (e.g. embedded images) are left untouched.
+pub fn to_markdown(content: &str) -> String {
+    let mut markdown = String::with_capacity(content.len());
+    let paged = content.contains("[newpage]");
+    if paged {
+        markdown.push_str(&page_anchor(1));
+    }
+
+    let mut page = 1;
+    let mut rest = content;
+    while let Some(start) = rest.find('[') {
+        push_text(&mut markdown, &rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(after) = rest.strip_prefix("[newpage]") {
+            page += 1;
+            markdown.push_str("\n\n---\n\n");
+            markdown.push_str(&page_anchor(page));
+            rest = after;
+            continue;
+        }
+
+        if let Some((title, after)) = tag(rest, "[chapter:", "]") {
+            markdown.push_str(&format!("\n\n## {}\n\n", title.trim()));
+            rest = after;
+            continue;
+        }
+
+        if let Some((inner, after)) = tag(rest, "[[rb:", "]]")
+            && let Some((base, ruby)) = inner.split_once('>')
+        {
+            markdown.push_str(&format!(
+                "<ruby>{}<rt>{}</rt></ruby>",
+                base.trim(),
+                ruby.trim()
+            ));
+            rest = after;
+            continue;
+        }
+
+        if let Some((inner, after)) = tag(rest, "[[jumpuri:", "]]")
+            && let Some((text, url)) = inner.split_once('>')
+        {
+            markdown.push_str(&format!("[{}]({})", text.trim(), url.trim()));
+            rest = after;
+            continue;
+        }
+
+        if let Some((target, after)) = tag(rest, "[jump:", "]")
+            && let Ok(target) = target.trim().parse::<u32>()
+        {
+            match paged {
+                true => markdown.push_str(&format!("[→ {target}](#page-{target})")),
+                false => markdown.push_str(&format!("→ {target}")),
+            }
+            rest = after;
+            continue;
+        }
+
+        markdown.push('[');
+        rest = &rest[1..];
+    }
+    push_text(&mut markdown, rest);
+
+    markdown
+}
+
+/// Plain text, with line breaks kept as hard breaks
+fn push_text(markdown: &mut String, text: &str) {
+    markdown.push_str(&text.replace('\n', "  \n"));
+}
+
+fn page_anchor(page: u32) -> String {
+    format!("<a id=\"page-{page}\"></a>\n\n")
+}
+
+/// Inner text and the rest after a `open ... close` tag at the start of `text`
+fn tag<'a>(text: &'a str, open: &str, close: &str) -> Option<(&'a str, &'a str)> {
+    let inner = text.strip_prefix(open)?;
+    let end = inner.find(close)?;
+    Some((&inner[..end], &inner[end + close.len()..]))
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use post_archiver::{
+    AuthorId, PlatformId, PostId,
+    importer::{UnsyncAuthor, UnsyncContent, UnsyncPost},
+};
+use post_archiver_utils::Result;
+use serde_json::Value;
+
+use crate::{
+    file::{ArchiveRequest, sanitize},
+    storage::{Storage, SyncedFiles},
+    user::PixivUserId,
+};
+
+/// Sidecar of every work in the plain layout, also used to find the works of earlier runs
+pub const METADATA: &str = "metadata.json";
+
+/// [`Storage`] of `--layout plain`, a `{user}/{work_id}_{title}/` folder per work without a database.
+///
+/// The ids only live for the run, works archived before are found again by the `url`
+/// of their `metadata.json`. There is nowhere to keep the sync of users, so every user is
+/// listed again on each run.
+#[derive(Debug)]
+pub struct PlainStorage {
+    root: PathBuf,
+    inner: Mutex<PlainInner>,
+}
+
+#[derive(Debug, Default)]
+struct PlainInner {
+    platforms: Vec<String>,
+    /// folder of each author, by [`AuthorId`]
+    authors: Vec<String>,
+    /// folder of each post, by [`PostId`]
+    posts: Vec<PathBuf>,
+    sources: HashMap<String, PostId>,
+}
+
+impl PlainInner {
+    fn add_post(&mut self, source: String, dir: PathBuf) -> PostId {
+        let id = PostId::from(self.posts.len() as u32);
+        self.posts.push(dir);
+        self.sources.insert(source, id);
+        id
+    }
+}
+
+impl PlainStorage {
+    pub fn open(root: &Path) -> Self {
+        let mut inner = PlainInner::default();
+        let dirs = fs::read_dir(root)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|user| user.path().is_dir())
+            .flat_map(|user| fs::read_dir(user.path()).into_iter().flatten().flatten())
+            .map(|work| work.path());
+        for dir in dirs {
+            if let Some(source) = read_source(&dir) {
+                inner.add_post(source, dir);
+            }
+        }
+
+        Self {
+            root: root.to_path_buf(),
+            inner: Mutex::new(inner),
+        }
+    }
+}
+
+fn read_source(dir: &Path) -> Option<String> {
+    let content = fs::read_to_string(dir.join(METADATA)).ok()?;
+    let metadata = serde_json::from_str::<Value>(&content).ok()?;
+    metadata["url"].as_str().map(str::to_string)
+}
+
+impl Storage for PlainStorage {
+    fn import_platform(&self, name: &str) -> Result<PlatformId> {
+        let mut inner = self.inner.lock().unwrap();
+        let index = match inner.platforms.iter().position(|platform| platform == name) {
+            Some(index) => index,
+            None => {
+                inner.platforms.push(name.to_string());
+                inner.platforms.len() - 1
+            }
+        };
+        Ok(PlatformId::from(index as u32))
+    }
+
+    fn find_post(&self, source: &str) -> Result<Option<PostId>> {
+        Ok(self.inner.lock().unwrap().sources.get(source).copied())
+    }
+
+    fn import_author(&self, author: UnsyncAuthor) -> Result<AuthorId> {
+        // the pixiv id comes first, and unlike the name it never changes
+        let name = match author.aliases.first() {
+            Some(alias) => alias.source.clone(),
+            None => author.name,
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.authors.push(sanitize(&name));
+        Ok(AuthorId::from((inner.authors.len() - 1) as u32))
+    }
+
+    // files are written straight to their folder, there is nothing to commit
+
+    fn begin(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn begin_post(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync_post(&self, post: UnsyncPost<ArchiveRequest>) -> Result<(PostId, SyncedFiles)> {
+        let mut inner = self.inner.lock().unwrap();
+        let (id, dir) = match inner.sources.get(&post.source).copied() {
+            Some(id) => (id, inner.posts[*id as usize].clone()),
+            None => {
+                let author = post
+                    .authors
+                    .first()
+                    .and_then(|author| inner.authors.get(**author as usize))
+                    .cloned()
+                    .unwrap_or_else(|| "unknown".to_string());
+                let work = post.source.trim_end_matches('/').rsplit('/').next();
+                let name = format!("{}_{}", work.unwrap_or_default(), post.title);
+                let name = sanitize(&name).chars().take(120).collect::<String>();
+                let dir = self.root.join(author).join(name.trim_end_matches(['.', ' ']));
+                (inner.add_post(post.source.clone(), dir.clone()), dir)
+            }
+        };
+
+        let mut files: SyncedFiles = vec![];
+        let contents = post.content.into_iter().filter_map(|content| match content {
+            UnsyncContent::File(file) => Some(file),
+            UnsyncContent::Text(_) => None,
+        });
+        // the thumb of illusts is their first page
+        for file in contents.chain(post.thumb) {
+            let path = dir.join(&file.filename);
+            if !files.iter().any(|(existing, _)| *existing == path) {
+                files.push((path, file.data));
+            }
+        }
+        Ok((id, files))
+    }
+
+    fn end_post(&self, _keep: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_file_extra(
+        &self,
+        _post: PostId,
+        _filename: &str,
+        _key: &str,
+        _value: Value,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn find_file(&self, _sha256: &str) -> Option<(PostId, String)> {
+        None
+    }
+
+    fn index_files(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn post_path(&self, _root: &Path, post: PostId) -> PathBuf {
+        self.inner.lock().unwrap().posts[*post as usize].clone()
+    }
+
+    fn sources(&self) -> Result<HashSet<String>> {
+        Ok(self.inner.lock().unwrap().sources.keys().cloned().collect())
+    }
+
+    fn posts_of_user(&self, user: PixivUserId) -> Result<Vec<(PostId, String)>> {
+        // the folder of an author is named after its pixiv id
+        let user = user.to_string();
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .sources
+            .iter()
+            .filter(|(_, id)| {
+                let dir = &inner.posts[***id as usize];
+                dir.parent().and_then(Path::file_name) == Some(OsStr::new(&user))
+            })
+            .map(|(source, id)| (*id, source.clone()))
+            .collect())
+    }
+
+    fn remove_post(&self, _root: &Path, post: PostId) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sources.retain(|_, id| *id != post);
+        match fs::remove_dir_all(&inner.posts[*post as usize]) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use log::{info, warn};
+use post_archiver_utils::ArchiveClient;
+use tokio::time::Instant;
+
+/// Proxies taking turns on the requests, skipping for a while the ones failing repeatedly
+#[derive(Debug, Default)]
+pub struct ProxyPool {
+    proxies: Vec<Proxy>,
+    next: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Proxy {
+    url: String,
+    client: ArchiveClient,
+    images: ArchiveClient,
+    failures: AtomicU32,
+    skipped_until: Mutex<Option<Instant>>,
+}
+
+impl ProxyPool {
+    const MAX_FAILURES: u32 = 3;
+    const SKIP: Duration = Duration::from_secs(600);
+
+    /// Proxies with their clients of the api and the image hosts
+    pub fn new(proxies: Vec<(String, ArchiveClient, ArchiveClient)>) -> Self {
+        Self {
+            proxies: proxies
+                .into_iter()
+                .map(|(url, client, images)| Proxy {
+                    url: redact(&url),
+                    client,
+                    images,
+                    failures: AtomicU32::new(0),
+                    skipped_until: Mutex::new(None),
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Index of the next proxy to use, `None` without proxies.
+    /// When every proxy is skipped, the rotation goes on regardless.
+    pub fn pick(&self) -> Option<usize> {
+        if self.proxies.is_empty() {
+            return None;
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let healthy = (0..self.proxies.len())
+            .map(|offset| (start + offset) % self.proxies.len())
+            .find(|&index| {
+                let mut skipped = self.proxies[index].skipped_until.lock().unwrap();
+                match *skipped {
+                    Some(until) if until > now => false,
+                    Some(_) => {
+                        *skipped = None;
+                        info!("[proxy] Trying {} again", self.proxies[index].url);
+                        true
+                    }
+                    None => true,
+                }
+            });
+        Some(healthy.unwrap_or(start % self.proxies.len()))
+    }
+
+    pub fn client(&self, index: usize, image: bool) -> &ArchiveClient {
+        match image {
+            true => &self.proxies[index].images,
+            false => &self.proxies[index].client,
+        }
+    }
+
+    /// Track the outcome of a request through the proxy
+    pub fn report(&self, index: Option<usize>, ok: bool) {
+        let Some(proxy) = index.map(|index| &self.proxies[index]) else {
+            return;
+        };
+
+        if ok {
+            proxy.failures.store(0, Ordering::Relaxed);
+            return;
+        }
+
+        let failures = proxy.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= Self::MAX_FAILURES {
+            proxy.failures.store(0, Ordering::Relaxed);
+            *proxy.skipped_until.lock().unwrap() = Some(Instant::now() + Self::SKIP);
+            warn!(
+                "[proxy] {} failed {failures} times in a row, skipping it for {}s",
+                proxy.url,
+                Self::SKIP.as_secs()
+            );
+        }
+    }
+}
+
+/// The proxy url with its `user:password@` hidden, safe to log
+pub fn redact(url: &str) -> String {
+    let (scheme, rest) = match url.split_once("://") {
+        Some((scheme, rest)) => (format!("{scheme}://"), rest),
+        None => (String::new(), url),
+    };
+    let authority = rest.find('/').map_or(rest, |end| &rest[..end]);
+    match authority.rfind('@') {
+        Some(at) => format!("{scheme}<redacted>@{}", &rest[at + 1..]),
+        None => url.to_string(),
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::Path,
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{Manager, config::PruneMode};
+
+/// What a listing task went through from start to end, compared by `--prune` with the last run
+#[derive(Debug, Clone)]
+pub enum Listed {
+    /// urls of the favorite works
+    Favorites(Vec<String>),
+    /// ids of the followed users
+    Following(Vec<u64>),
+}
+
+/// Favorites and followed users of the last complete listing, saved as `listing.json`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Listing {
+    #[serde(default)]
+    pub favorites: BTreeSet<String>,
+    #[serde(default)]
+    pub following: BTreeSet<u64>,
+}
+
+impl Listing {
+    fn load(path: &Path) -> Self {
+        fs::read(path)
+            .ok()
+            .and_then(|data| {
+                serde_json::from_slice(&data)
+                    .inspect_err(|e| warn!("[prune] Ignoring invalid {}: {e}", path.display()))
+                    .ok()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Flag or remove the archived posts which left the favorites or followed users since the last run.
+/// `favorites` and `following` are `None` when this run didn't list them completely.
+pub async fn prune(
+    manager: &Manager,
+    root: &Path,
+    mode: PruneMode,
+    favorites: Option<BTreeSet<String>>,
+    following: Option<BTreeSet<u64>>,
+) {
+    let path = root.join("listing.json");
+    let mut listing = Listing::load(&path);
+    let manager = manager.lock().await;
+
+    let mut pruned = BTreeMap::new();
+    if let Some(favorites) = favorites {
+        for url in listing.favorites.difference(&favorites) {
+            if let Ok(Some(post)) = manager.find_post(url) {
+                pruned.insert(url.clone(), (post, "no longer a favorite".to_string()));
+            }
+        }
+        listing.favorites = favorites;
+    }
+    if let Some(following) = following {
+        for user in listing.following.difference(&following) {
+            let posts = match manager.posts_of_user(*user) {
+                Ok(posts) => posts,
+                Err(e) => {
+                    error!("[prune] Failed to find the posts of user {user}: {e}");
+                    continue;
+                }
+            };
+            // still a favorite, so still wanted
+            let posts = posts
+                .into_iter()
+                .filter(|(_, url)| !listing.favorites.contains(url));
+            for (post, url) in posts {
+                pruned.insert(url, (post, format!("user {user} no longer followed")));
+            }
+        }
+        listing.following = following;
+    }
+
+    let saved = serde_json::to_vec_pretty(&listing)
+        .map_err(io::Error::from)
+        .and_then(|data| fs::write(&path, data));
+    if let Err(e) = saved {
+        error!("[prune] Failed to save {}: {e}", path.display());
+    }
+
+    if pruned.is_empty() {
+        info!("[prune] Nothing to prune");
+        return;
+    }
+
+    match mode {
+        PruneMode::Flag => {
+            let path = root.join("pruned.json");
+            let mut flagged = fs::read(&path)
+                .ok()
+                .and_then(|data| serde_json::from_slice::<BTreeMap<String, String>>(&data).ok())
+                .unwrap_or_default();
+            for (url, (_, reason)) in pruned {
+                warn!("[prune] {url}: {reason}");
+                flagged.insert(url, reason);
+            }
+
+            let saved = serde_json::to_vec_pretty(&flagged)
+                .map_err(io::Error::from)
+                .and_then(|data| fs::write(&path, data));
+            match saved {
+                Ok(()) => info!("[prune] Flagged posts are listed in {}", path.display()),
+                Err(e) => error!("[prune] Failed to save {}: {e}", path.display()),
+            }
+        }
+        PruneMode::Remove => {
+            for (url, (post, reason)) in pruned {
+                match manager.remove_post(root, post) {
+                    Ok(()) => warn!("[prune] Removed {url}: {reason}"),
+                    Err(e) => error!("[prune] Failed to remove {url}: {e}"),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use clap::ValueEnum;
+use log::{debug, error, info};
+use plyne::Input;
+use serde::Deserialize;
+
+use crate::{Config, api::PixivClient, artwork::PixivArtworkId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[clap(rename_all = "snake_case")]
+pub enum RankingMode {
+    Daily,
+    Weekly,
+    Monthly,
+    Rookie,
+    Original,
+    DailyAi,
+    Male,
+    Female,
+    DailyR18,
+    WeeklyR18,
+    DailyR18Ai,
+    MaleR18,
+    FemaleR18,
+    R18g,
+}
+
+impl RankingMode {
+    pub fn param(&self) -> &'static str {
+        match self {
+            RankingMode::Daily => "daily",
+            RankingMode::Weekly => "weekly",
+            RankingMode::Monthly => "monthly",
+            RankingMode::Rookie => "rookie",
+            RankingMode::Original => "original",
+            RankingMode::DailyAi => "daily_ai",
+            RankingMode::Male => "male",
+            RankingMode::Female => "female",
+            RankingMode::DailyR18 => "daily_r18",
+            RankingMode::WeeklyR18 => "weekly_r18",
+            RankingMode::DailyR18Ai => "daily_r18_ai",
+            RankingMode::MaleR18 => "male_r18",
+            RankingMode::FemaleR18 => "female_r18",
+            RankingMode::R18g => "r18g",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PixivRanking {
+    pub contents: Vec<PixivRankingWork>,
+    pub date: String,
+    #[serde(default)]
+    pub next: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PixivRankingWork {
+    pub illust_id: u64,
+}
+
+pub async fn reslove_ranking(
+    artworks_pipeline: Input<PixivArtworkId>,
+    client: &PixivClient,
+    config: &Config,
+) {
+    let Some(mode) = config.ranking else {
+        debug!("[ranking] Skipping ranking archiving");
+        return;
+    };
+
+    let date = config
+        .ranking_date
+        .as_ref()
+        .map(|date| format!("&date={}", date.replace('-', "")))
+        .unwrap_or_default();
+
+    let mut page = 1;
+    loop {
+        let url = format!(
+            "https://www.pixiv.net/ranking.php?mode={}&content=all&p={page}&format=json{date}",
+            mode.param()
+        );
+
+        let ranking = match client.fetch_raw::<PixivRanking>(&url).await {
+            Ok(ranking) => ranking,
+            Err(e) => {
+                error!("[ranking] Failed to fetch {} page {page}: {e:?}", mode.param());
+                return;
+            }
+        };
+
+        info!(
+            "[ranking] Found {} works of {} ({}) on page {page}",
+            ranking.contents.len(),
+            mode.param(),
+            ranking.date
+        );
+        for work in ranking.contents {
+            artworks_pipeline
+                .send(PixivArtworkId::Illust(work.illust_id))
+                .unwrap();
+        }
+
+        // `next` is the next page number, or `false` on the last page
+        match ranking.next.as_u64() {
+            Some(next) => page = next,
+            None => break,
+        }
+    }
+
+    info!("[ranking] Resolve finished");
+}
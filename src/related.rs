@@ -0,0 +1,51 @@
+use log::error;
+use serde::Deserialize;
+
+use crate::{api::PixivClient, artwork::PixivArtworkId};
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct PixivRelated {
+    #[serde(default)]
+    pub illusts: Vec<PixivRelatedWork>,
+    #[serde(default)]
+    pub novels: Vec<PixivRelatedWork>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PixivRelatedWork {
+    // ads are mixed into the list without an id
+    pub id: Option<String>,
+}
+
+pub async fn fetch_related(client: &PixivClient, id: &PixivArtworkId) -> Vec<PixivArtworkId> {
+    let url = match id {
+        PixivArtworkId::Illust(id) => {
+            format!("https://www.pixiv.net/ajax/illust/{id}/recommend/init?limit=18&lang=ja")
+        }
+        PixivArtworkId::Novel(id) => {
+            format!("https://www.pixiv.net/ajax/novel/{id}/recommend/init?limit=20&lang=ja")
+        }
+        PixivArtworkId::UnlistedIllust(_) | PixivArtworkId::UnlistedNovel(_) => return vec![],
+    };
+
+    let related = match client.fetch::<PixivRelated>(&url).await {
+        Ok(related) => related,
+        Err(e) => {
+            error!("[related] Failed to fetch related works of {}: {e:?}", id.url());
+            return vec![];
+        }
+    };
+
+    let parse = |works: Vec<PixivRelatedWork>| {
+        works
+            .into_iter()
+            .filter_map(|work| work.id?.parse::<u64>().ok())
+            .collect::<Vec<_>>()
+    };
+
+    parse(related.illusts)
+        .into_iter()
+        .map(PixivArtworkId::Illust)
+        .chain(parse(related.novels).into_iter().map(PixivArtworkId::Novel))
+        .collect()
+}
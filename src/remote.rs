@@ -0,0 +1,148 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use s3::{Bucket, Region, creds::Credentials, error::S3Error};
+use tokio::fs::File;
+
+use crate::config::Config;
+
+/// S3-compatible bucket of `--s3-endpoint`, the archived files are uploaded to it
+/// instead of being written to the output folder.
+///
+/// Each file keeps its path in the output folder as key, after `--s3-prefix`. The options
+/// reading the archived files back (`--update`, `--dedupe`, `--max-pages`) are rejected with it.
+#[derive(Debug, Clone)]
+pub struct Remote {
+    bucket: Box<Bucket>,
+    prefix: String,
+    root: PathBuf,
+}
+
+impl Remote {
+    /// The bucket of the config, if any
+    pub fn open(config: &Config) -> Result<Option<Self>, S3Error> {
+        let (Some(endpoint), Some(bucket)) = (&config.s3_endpoint, &config.s3_bucket) else {
+            return Ok(None);
+        };
+
+        let region = Region::Custom {
+            region: config.s3_region.clone(),
+            endpoint: endpoint.clone(),
+        };
+        // without keys, they are read from the environment or the aws profile
+        let credentials = Credentials::new(
+            config.s3_access_key.as_deref(),
+            config.s3_secret_key.as_deref(),
+            None,
+            None,
+            None,
+        )?;
+        // self-hosted servers (MinIO, Garage, NAS) rarely route bucket subdomains
+        let bucket = Bucket::new(bucket, region, credentials)?.with_path_style();
+
+        Ok(Some(Self {
+            bucket,
+            prefix: config.s3_prefix.trim_matches('/').to_string(),
+            root: config.output.clone(),
+        }))
+    }
+
+    /// Key of the file at `path` in the output folder
+    fn key(&self, path: &Path) -> String {
+        let relative = path
+            .strip_prefix(&self.root)
+            .unwrap_or(path)
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        match self.prefix.is_empty() {
+            true => relative,
+            false => format!("{}/{relative}", self.prefix),
+        }
+    }
+
+    /// Stream the file at `src` to the key of `path`
+    pub async fn upload(&self, src: &Path, path: &Path) -> io::Result<()> {
+        let mut file = File::open(src).await?;
+        let response = self
+            .bucket
+            .put_object_stream(&mut file, self.key(path))
+            .await
+            .map_err(io::Error::other)?;
+        check_status(response.status_code(), path)
+    }
+
+    /// Upload `content` to the key of `path`
+    pub async fn put(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let response = self
+            .bucket
+            .put_object(self.key(path), content)
+            .await
+            .map_err(io::Error::other)?;
+        check_status(response.status_code(), path)
+    }
+
+    /// Content of the key of `path`, `None` if there is no such object
+    pub async fn get(&self, path: &Path) -> io::Result<Option<Vec<u8>>> {
+        let response = self
+            .bucket
+            .get_object(self.key(path))
+            .await
+            .map_err(io::Error::other)?;
+        match response.status_code() {
+            404 => Ok(None),
+            status => check_status(status, path).map(|()| Some(response.to_vec())),
+        }
+    }
+}
+
+fn check_status(status: u16, path: &Path) -> io::Result<()> {
+    match status {
+        200..300 => Ok(()),
+        status => Err(io::Error::other(format!(
+            "request for {} failed with status {status}",
+            path.display()
+        ))),
+    }
+}
+
+/// Write a file of the output folder, to the bucket with `--s3-endpoint`
+pub async fn write_file(remote: Option<&Remote>, path: &Path, content: &[u8]) -> io::Result<()> {
+    match remote {
+        Some(remote) => remote.put(path, content).await,
+        None => {
+            if let Some(dir) = path.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            tokio::fs::write(path, content).await
+        }
+    }
+}
+
+/// Copy a downloaded file into the output folder, to the bucket with `--s3-endpoint`
+pub async fn copy_file(remote: Option<&Remote>, src: &Path, path: &Path) -> io::Result<()> {
+    match remote {
+        Some(remote) => remote.upload(src, path).await,
+        None => {
+            if let Some(dir) = path.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            tokio::fs::copy(src, path).await.map(|_| ())
+        }
+    }
+}
+
+/// Read a file of the output folder, from the bucket with `--s3-endpoint`
+pub async fn read_file(remote: Option<&Remote>, path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match remote {
+        Some(remote) => remote.get(path).await,
+        None => match tokio::fs::read(path).await {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        },
+    }
+}
@@ -0,0 +1,157 @@
+use clap::ValueEnum;
+use log::{debug, error, info};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use plyne::Input;
+use serde::Deserialize;
+use tokio::task::JoinSet;
+
+use crate::{Config, api::PixivClient, artwork::PixivArtworkId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum SearchMode {
+    /// Partial match of tags
+    #[default]
+    Tag,
+    /// Exact match of tags
+    TagFull,
+    /// Title and caption
+    Title,
+}
+
+impl SearchMode {
+    pub fn param(&self) -> &'static str {
+        match self {
+            SearchMode::Tag => "s_tag",
+            SearchMode::TagFull => "s_tag_full",
+            SearchMode::Title => "s_tc",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum SearchType {
+    /// Illusts, manga and ugoira
+    #[default]
+    Artworks,
+    Illusts,
+    Manga,
+    Novels,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PixivSearch {
+    pub illust_manga: Option<PixivSearchResult>,
+    pub illust: Option<PixivSearchResult>,
+    pub manga: Option<PixivSearchResult>,
+    pub novel: Option<PixivSearchResult>,
+}
+
+impl PixivSearch {
+    pub fn into_result(self) -> Option<PixivSearchResult> {
+        self.illust_manga
+            .or(self.illust)
+            .or(self.manga)
+            .or(self.novel)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PixivSearchResult {
+    pub data: Vec<PixivSearchWork>,
+    pub total: u64,
+    #[serde(default)]
+    pub last_page: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PixivSearchWork {
+    // ads are mixed into the list without an id
+    pub id: Option<String>,
+}
+
+pub async fn reslove_search(
+    artworks_pipeline: Input<PixivArtworkId>,
+    client: &PixivClient,
+    config: &Config,
+) {
+    if config.search.is_empty() {
+        debug!("[search] Skipping search archiving");
+        return;
+    }
+
+    let mut join_set = JoinSet::new();
+    for word in config.search.iter().cloned() {
+        info!("[search] Archive search: {word}");
+        join_set.spawn(reslove_search_word(
+            artworks_pipeline.clone(),
+            client.clone(),
+            word,
+            config.search_mode,
+            config.search_type,
+            config.search_pages,
+        ));
+    }
+
+    join_set.join_all().await;
+    info!("[search] Resolve finished");
+}
+
+async fn reslove_search_word(
+    tx: Input<PixivArtworkId>,
+    client: PixivClient,
+    word: String,
+    mode: SearchMode,
+    ty: SearchType,
+    max_pages: u32,
+) {
+    let encoded = utf8_percent_encode(&word, NON_ALPHANUMERIC);
+    let (path, type_param) = match ty {
+        SearchType::Artworks => ("artworks", "all"),
+        SearchType::Illusts => ("illustrations", "illust_and_ugoira"),
+        SearchType::Manga => ("manga", "manga"),
+        SearchType::Novels => ("novels", "all"),
+    };
+
+    let mut page = 1;
+    while page <= max_pages {
+        let url = format!(
+            "https://www.pixiv.net/ajax/search/{path}/{encoded}?word={encoded}&order=date_d&mode=all&p={page}&s_mode={}&type={type_param}&lang=ja",
+            mode.param()
+        );
+
+        let result = match client.fetch::<PixivSearch>(&url).await {
+            Ok(response) => response.into_result(),
+            Err(e) => {
+                error!("[search] Failed to fetch {word} page {page}: {e:?}");
+                return;
+            }
+        };
+
+        let Some(result) = result.filter(|result| !result.data.is_empty()) else {
+            break;
+        };
+
+        info!(
+            "[search] Found {} works of {word} on page {page} ({} total)",
+            result.data.len(),
+            result.total
+        );
+        for work in result.data {
+            let Some(id) = work.id.and_then(|id| id.parse().ok()) else {
+                continue;
+            };
+            let id = match ty {
+                SearchType::Novels => PixivArtworkId::Novel(id),
+                _ => PixivArtworkId::Illust(id),
+            };
+            tx.send(id).unwrap();
+        }
+
+        if result.last_page != 0 && page >= result.last_page {
+            break;
+        }
+        page += 1;
+    }
+}
@@ -1,12 +1,21 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
 use log::{debug, error, info};
 use plyne::{Input, Output};
 use serde::Deserialize;
+use serde_json::json;
 use tokio::{sync::mpsc::UnboundedSender, task::JoinSet};
 
 use crate::{
+    Manager,
     api::PixivClient,
     artwork::PixivArtworkId,
     config::{Config, Progress},
+    remote::{Remote, copy_file, read_file, write_file},
+    storage::{Metadata, Storage},
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
@@ -29,6 +38,201 @@ impl PixivSeriesId {
             PixivSeriesId::Novel(id) => format!("https://www.pixiv.net/novel/series/{id}"),
         }
     }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PixivSeriesId::Illust(_) => "illust",
+            PixivSeriesId::Novel(_) => "novel",
+        }
+    }
+
+    /// Folder of the series metadata, `collections/{illust|novel}-{id}`
+    pub fn dir(&self, root: &Path) -> PathBuf {
+        root.join("collections").join(format!("{}-{}", self.kind(), self.id()))
+    }
+}
+
+/// Series metadata, which has no place in the collection itself
+#[derive(Debug, Clone)]
+pub struct PixivSeriesDetail {
+    pub series: PixivSeriesId,
+    pub title: String,
+    pub caption: String,
+    pub total: u64,
+    pub cover: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PixivIllustSeriesDetail {
+    illust_series: Vec<PixivIllustSeriesInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct PixivIllustSeriesInfo {
+    id: String,
+    title: String,
+    #[serde(default)]
+    caption: String,
+    #[serde(default)]
+    total: u64,
+    url: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PixivNovelSeriesDetail {
+    title: String,
+    #[serde(default)]
+    caption: String,
+    #[serde(default, alias = "publishedContentCount")]
+    total: u64,
+    cover: Option<PixivNovelSeriesCover>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct PixivNovelSeriesCover {
+    urls: PixivNovelSeriesCoverUrls,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct PixivNovelSeriesCoverUrls {
+    original: String,
+}
+
+pub async fn fetch_series_detail(
+    client: &PixivClient,
+    series: PixivSeriesId,
+) -> Option<PixivSeriesDetail> {
+    let id = series.id();
+    let detail = match series {
+        PixivSeriesId::Illust(_) => client
+            .fetch::<PixivIllustSeriesDetail>(&format!(
+                "https://www.pixiv.net/ajax/series/{id}?lang=ja&p=1"
+            ))
+            .await
+            .map(|detail| {
+                detail
+                    .illust_series
+                    .into_iter()
+                    .find(|info| info.id == id.to_string())
+                    .map(|info| PixivSeriesDetail {
+                        series,
+                        title: info.title,
+                        caption: info.caption,
+                        total: info.total,
+                        cover: info.url,
+                    })
+            }),
+        PixivSeriesId::Novel(_) => client
+            .fetch::<PixivNovelSeriesDetail>(&format!(
+                "https://www.pixiv.net/ajax/novel/series/{id}?lang=ja"
+            ))
+            .await
+            .map(|detail| {
+                Some(PixivSeriesDetail {
+                    series,
+                    title: detail.title,
+                    caption: detail.caption,
+                    total: detail.total,
+                    cover: detail.cover.map(|cover| cover.urls.original),
+                })
+            }),
+    };
+
+    detail
+        .inspect_err(|e| error!("[series] Failed to fetch detail of {}: {e:?}", series.url()))
+        .ok()
+        .flatten()
+}
+
+/// Save the cover under `collections/{illust|novel}-{id}`, and the description and total
+/// in the storage, or as `series.json` next to the cover without a database
+pub async fn save_series_detail(
+    client: &PixivClient,
+    detail: &PixivSeriesDetail,
+    root: &Path,
+    remote: Option<&Remote>,
+    manager: &Manager,
+) {
+    let dir = detail.series.dir(root);
+
+    if let Some(url) = &detail.cover {
+        let ext = url.rsplit_once('.').map(|(_, ext)| ext).unwrap_or("jpg");
+        let dst = dir.join(format!("cover.{ext}"));
+        match client.download(url).await {
+            Ok(temp) => {
+                if let Err(e) = copy_file(remote, &temp, &dst).await {
+                    error!("[series] Failed to save {}: {e}", dst.display());
+                }
+            }
+            Err(e) => error!("[series] Failed to download cover of {}: {e}", detail.series.url()),
+        }
+    }
+
+    let metadata = json!({
+        "id": detail.series.id(),
+        "type": detail.series.kind(),
+        "title": detail.title,
+        "description": detail.caption,
+        "total": detail.total,
+        "url": detail.series.url(),
+    });
+    let kept = manager
+        .lock()
+        .await
+        .set_metadata(&Metadata::Series(detail.series), &metadata);
+    match kept {
+        Ok(true) => {}
+        Ok(false) => {
+            let path = dir.join("series.json");
+            let content = serde_json::to_vec_pretty(&metadata).unwrap();
+            if let Err(e) = write_file(remote, &path, &content).await {
+                error!("[series] Failed to save {}: {e}", path.display());
+            }
+        }
+        Err(e) => error!("[series] Failed to save detail of {}: {e}", detail.series.url()),
+    }
+}
+
+/// Record the position of a work in the series, as collections have no order.
+/// Kept in the storage, or as `order.json` of the series folder without a database.
+pub async fn record_series_order(
+    storage: &dyn Storage,
+    root: &Path,
+    remote: Option<&Remote>,
+    series: PixivSeriesId,
+    order: u32,
+    source: &str,
+) {
+    let metadata = Metadata::SeriesOrder(series);
+    let path = series.dir(root).join("order.json");
+    let orders = match storage.metadata(&metadata) {
+        Ok(Some(orders)) => Some(orders),
+        Ok(None) => read_file(remote, &path).await.ok().flatten().and_then(|data| {
+            serde_json::from_slice(&data).ok()
+        }),
+        Err(e) => {
+            error!("[series] Failed to read order of {}: {e}", series.url());
+            return;
+        }
+    };
+    let mut orders: BTreeMap<u32, String> = orders
+        .and_then(|orders| serde_json::from_value(orders).ok())
+        .unwrap_or_default();
+    orders.insert(order, source.to_string());
+
+    let orders = json!(orders);
+    match storage.set_metadata(&metadata, &orders) {
+        Ok(true) => {}
+        Ok(false) => {
+            let content = serde_json::to_vec_pretty(&orders).unwrap();
+            if let Err(e) = write_file(remote, &path, &content).await {
+                error!("[series] Failed to save {}: {e}", path.display());
+            }
+        }
+        Err(e) => error!("[series] Failed to save order of {}: {e}", series.url()),
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -64,7 +268,7 @@ pub async fn reslove_series(
     client: &PixivClient,
 ) {
     let mut join_set = JoinSet::new();
-    let pb = Progress::new(config.multi.clone(), "series");
+    let pb = Progress::new(config, "series");
 
     debug!("[series] Waiting for series to resolve");
     while let Some(series) = series_pipeline.recv().await {
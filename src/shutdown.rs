@@ -0,0 +1,52 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+use log::warn;
+use tokio::sync::Notify;
+
+/// Graceful shutdown requested by Ctrl+C, in-flight works are still archived
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+impl Shutdown {
+    /// Request the shutdown on the first Ctrl+C, exit at once on the second one
+    pub fn listen(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            warn!("[main] Interrupted, finishing in-flight works (press Ctrl+C again to force)");
+            shutdown.request();
+
+            if tokio::signal::ctrl_c().await.is_ok() {
+                warn!("[main] Forced exit");
+                std::process::exit(130);
+            }
+        });
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    pub async fn wait(&self) {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if self.is_requested() {
+            return;
+        }
+        notified.await;
+    }
+}
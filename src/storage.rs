@@ -0,0 +1,302 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use post_archiver::{
+    AuthorId, PlatformId, PostId,
+    importer::{UnsyncAuthor, UnsyncPost},
+    manager::PostArchiverManager,
+};
+use log::info;
+use rusqlite::OptionalExtension;
+use post_archiver_utils::{Error, Result, get_post_path};
+use serde_json::Value;
+
+use crate::{
+    config::{Config, Layout},
+    file::ArchiveRequest,
+    plain::PlainStorage,
+    series::PixivSeriesId,
+    user::{NewestWorks, PixivUserId},
+};
+
+/// Where each file of a synced post goes, with the request it is downloaded by
+pub type SyncedFiles = Vec<(PathBuf, ArchiveRequest)>;
+
+/// Backend the archived posts are written to, a PostArchiver folder by default.
+/// Other backends are given to [`crate::Archiver::with_storage`].
+///
+/// The archive stage only goes through this trait. Posts are written in batches between
+/// [`Storage::begin`] and [`Storage::commit`], each one between [`Storage::begin_post`]
+/// and [`Storage::end_post`], so a post whose files fail to save is dropped alone.
+pub trait Storage {
+    fn import_platform(&self, name: &str) -> Result<PlatformId>;
+    /// The post archived from `source`
+    fn find_post(&self, source: &str) -> Result<Option<PostId>>;
+    fn import_author(&self, author: UnsyncAuthor) -> Result<AuthorId>;
+
+    fn begin(&self) -> Result<()>;
+    fn begin_post(&self) -> Result<()>;
+    /// Store the post, returning its id and where each of its files goes
+    fn sync_post(&self, post: UnsyncPost<ArchiveRequest>) -> Result<(PostId, SyncedFiles)>;
+    /// Keep or drop what was written since [`Storage::begin_post`]
+    fn end_post(&self, keep: bool) -> Result<()>;
+    fn commit(&self) -> Result<()>;
+
+    /// Set `key` in the extra of a stored file, e.g. its SHA-256
+    fn set_file_extra(&self, post: PostId, filename: &str, key: &str, value: Value)
+    -> Result<()>;
+    /// Stored file with this SHA-256, as its post and filename
+    fn find_file(&self, sha256: &str) -> Option<(PostId, String)>;
+    /// Speed up [`Storage::find_file`], called once before deduplicating
+    fn index_files(&self) -> Result<()>;
+
+    /// Folder of the post in the output folder `root`
+    fn post_path(&self, root: &Path, post: PostId) -> PathBuf {
+        get_post_path(root, post)
+    }
+
+    /// Sources of every stored post, for `--incremental`
+    fn sources(&self) -> Result<HashSet<String>>;
+    /// Stored posts of the pixiv user with their source, for `--prune`
+    fn posts_of_user(&self, user: PixivUserId) -> Result<Vec<(PostId, String)>>;
+    /// Delete the post with its files, for `--prune`
+    fn remove_post(&self, root: &Path, post: PostId) -> Result<()>;
+
+    /// Newest works of the users listed by earlier runs (see [`crate::user::UserSync`]),
+    /// backends without a place to keep them list every user on each run
+    fn user_sync(&self) -> Result<HashMap<PixivUserId, NewestWorks>> {
+        Ok(HashMap::new())
+    }
+    fn save_user_sync(&self, _user: PixivUserId, _newest: NewestWorks) -> Result<()> {
+        Ok(())
+    }
+
+    /// Keep pixiv metadata without a place in the posts, `false` when the backend has no
+    /// database for it, the caller then writes it as a json file
+    fn set_metadata(&self, _metadata: &Metadata, _value: &Value) -> Result<bool> {
+        Ok(false)
+    }
+    fn metadata(&self, _metadata: &Metadata) -> Result<Option<Value>> {
+        Ok(None)
+    }
+}
+
+/// Pixiv metadata without a place in PostArchiver, see [`Storage::set_metadata`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Metadata {
+    /// Bookmark, like, view and comment counts of a post, refreshed on every run
+    Stats(PostId),
+    /// Bio, region and social links of a user, by pixiv id
+    Profile(String),
+    /// Title, description and total of a series
+    Series(PixivSeriesId),
+    /// Source of each work of a series by its position, as collections have no order
+    SeriesOrder(PixivSeriesId),
+}
+
+impl Metadata {
+    /// Kind and id of the row, with the post it belongs to
+    fn key(&self) -> (&'static str, String, Option<PostId>) {
+        match self {
+            Metadata::Stats(post) => ("stats", (**post).to_string(), Some(*post)),
+            Metadata::Profile(user) => ("profile", user.clone(), None),
+            Metadata::Series(series) => ("series", series.url(), None),
+            Metadata::SeriesOrder(series) => ("series_order", series.url(), None),
+        }
+    }
+}
+
+/// Metadata of [`Storage::set_metadata`], removed with their post by [`Storage::remove_post`]
+const METADATA_TABLE: &str = "CREATE TABLE IF NOT EXISTS pixiv_metadata (kind TEXT NOT NULL, id TEXT NOT NULL, post INTEGER REFERENCES posts(id), value TEXT NOT NULL, updated_at INTEGER NOT NULL, PRIMARY KEY (kind, id))";
+
+/// The storage of `--layout`
+pub fn open(config: &Config) -> Result<Box<dyn Storage + Send>> {
+    Ok(match config.layout {
+        Layout::PostArchiver => {
+            info!("[main] Connecting to PostArchiver");
+            Box::new(PostArchiverManager::open_or_create(&config.output)?)
+        }
+        Layout::Plain => Box::new(PlainStorage::open(&config.output)),
+    })
+}
+
+impl Storage for PostArchiverManager {
+    fn import_platform(&self, name: &str) -> Result<PlatformId> {
+        PostArchiverManager::import_platform(self, name.to_string()).map_err(Error::from)
+    }
+
+    fn find_post(&self, source: &str) -> Result<Option<PostId>> {
+        PostArchiverManager::find_post(self, source).map_err(Error::from)
+    }
+
+    fn import_author(&self, author: UnsyncAuthor) -> Result<AuthorId> {
+        author.sync(self).map_err(Error::from)
+    }
+
+    fn begin(&self) -> Result<()> {
+        Ok(self.conn().execute_batch("BEGIN")?)
+    }
+
+    fn begin_post(&self) -> Result<()> {
+        Ok(self.conn().execute_batch("SAVEPOINT post")?)
+    }
+
+    fn sync_post(&self, post: UnsyncPost<ArchiveRequest>) -> Result<(PostId, SyncedFiles)> {
+        post.sync(self).map_err(Error::from)
+    }
+
+    fn end_post(&self, keep: bool) -> Result<()> {
+        let sql = match keep {
+            true => "RELEASE post",
+            false => "ROLLBACK TO post; RELEASE post",
+        };
+        Ok(self.conn().execute_batch(sql)?)
+    }
+
+    fn commit(&self) -> Result<()> {
+        if let Err(e) = self.conn().execute_batch("COMMIT") {
+            // leave no transaction open for the next batch
+            let _ = self.conn().execute_batch("ROLLBACK");
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    fn set_file_extra(
+        &self,
+        post: PostId,
+        filename: &str,
+        key: &str,
+        value: Value,
+    ) -> Result<()> {
+        self.conn().execute(
+            "UPDATE file_metas SET extra = json_set(COALESCE(extra, '{}'), '$.' || ?1, json(?2)) WHERE post = ?3 AND filename = ?4",
+            rusqlite::params![key, value.to_string(), post, filename],
+        )?;
+        Ok(())
+    }
+
+    fn find_file(&self, sha256: &str) -> Option<(PostId, String)> {
+        self.conn()
+            .query_row(
+                "SELECT post, filename FROM file_metas WHERE json_extract(extra, '$.sha256') = ?1 LIMIT 1",
+                [sha256],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .ok()
+    }
+
+    fn index_files(&self) -> Result<()> {
+        Ok(self.conn().execute_batch(
+            "CREATE INDEX IF NOT EXISTS file_metas_sha256 ON file_metas(json_extract(extra, '$.sha256'))",
+        )?)
+    }
+
+    fn sources(&self) -> Result<HashSet<String>> {
+        Ok(self
+            .conn()
+            .prepare("SELECT source FROM posts WHERE source IS NOT NULL")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn posts_of_user(&self, user: PixivUserId) -> Result<Vec<(PostId, String)>> {
+        Ok(self
+            .conn()
+            .prepare(
+                "SELECT posts.id, posts.source FROM posts
+                JOIN author_posts ON author_posts.post = posts.id
+                JOIN author_aliases ON author_aliases.target = author_posts.author
+                JOIN platforms ON platforms.id = author_aliases.platform
+                WHERE platforms.name = 'pixiv' AND author_aliases.source = ?1 AND posts.source IS NOT NULL",
+            )?
+            .query_map([user.to_string()], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// The rows linking to the post (its files, authors, tags, collections, ...) are deleted
+    /// with it in one transaction, archives may not enforce the foreign keys
+    fn remove_post(&self, root: &Path, post: PostId) -> Result<()> {
+        let tx = self.conn().unchecked_transaction()?;
+        let tables = |column: &str| {
+            tx.prepare(
+                "SELECT m.name FROM sqlite_master m JOIN pragma_table_info(m.name) c
+                 WHERE m.type = 'table' AND m.name != 'posts' AND c.name = ?1",
+            )?
+            .query_map([column], |row| row.get::<_, String>(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()
+        };
+        // thumbs of authors, collections and the post itself may be files of the post
+        for table in tables("thumb")?.iter().map(String::as_str).chain(["posts"]) {
+            tx.execute(
+                &format!(
+                    "UPDATE {table} SET thumb = NULL WHERE thumb IN (SELECT id FROM file_metas WHERE post = ?1)"
+                ),
+                [post],
+            )?;
+        }
+        for table in tables("post")? {
+            tx.execute(&format!("DELETE FROM {table} WHERE post = ?1"), [post])?;
+        }
+        tx.execute("DELETE FROM posts WHERE id = ?1", [post])?;
+        tx.commit()?;
+
+        match fs::remove_dir_all(get_post_path(root, post)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn user_sync(&self) -> Result<HashMap<PixivUserId, NewestWorks>> {
+        let conn = self.conn();
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS pixiv_user_sync (user INTEGER PRIMARY KEY, newest_illust INTEGER, newest_novel INTEGER, synced_at INTEGER NOT NULL)",
+        )?;
+        Ok(conn
+            .prepare("SELECT user, newest_illust, newest_novel FROM pixiv_user_sync")?
+            .query_map([], |row| {
+                let newest = NewestWorks {
+                    illust: row.get(1)?,
+                    novel: row.get(2)?,
+                };
+                Ok((row.get(0)?, newest))
+            })?
+            .collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn save_user_sync(&self, user: PixivUserId, newest: NewestWorks) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO pixiv_user_sync (user, newest_illust, newest_novel, synced_at) VALUES (?1, ?2, ?3, unixepoch())",
+            rusqlite::params![user, newest.illust, newest.novel],
+        )?;
+        Ok(())
+    }
+
+    fn set_metadata(&self, metadata: &Metadata, value: &Value) -> Result<bool> {
+        let conn = self.conn();
+        conn.execute_batch(METADATA_TABLE)?;
+        let (kind, id, post) = metadata.key();
+        conn.execute(
+            "INSERT OR REPLACE INTO pixiv_metadata (kind, id, post, value, updated_at) VALUES (?1, ?2, ?3, ?4, unixepoch())",
+            rusqlite::params![kind, id, post, value.to_string()],
+        )?;
+        Ok(true)
+    }
+
+    fn metadata(&self, metadata: &Metadata) -> Result<Option<Value>> {
+        let conn = self.conn();
+        conn.execute_batch(METADATA_TABLE)?;
+        let (kind, id, _) = metadata.key();
+        let value = conn
+            .query_row(
+                "SELECT value FROM pixiv_metadata WHERE kind = ?1 AND id = ?2",
+                rusqlite::params![kind, id],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+        Ok(value.and_then(|value| serde_json::from_str(&value).ok()))
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use post_archiver::{PlatformId, importer::UnsyncTag};
 use serde::Deserialize;
 
@@ -11,20 +13,34 @@ pub struct PixivTags {
 }
 
 impl PixivTags {
-    pub fn into_tags(&self, platform: PlatformId) -> Vec<UnsyncTag> {
-        self.tags
-            .iter()
-            .map(|tag| {
-                let name = tag.tag.clone();
+    /// Tags of the artwork, with their translation in `language` (e.g. `en`) as an extra tag
+    pub fn into_tags(&self, platform: PlatformId, language: Option<&str>) -> Vec<UnsyncTag> {
+        let mut tags: Vec<UnsyncTag> = vec![];
+        for tag in &self.tags {
+            let name = tag.tag.clone();
+
+            let is_r18 = matches!(name.as_str(), "R-18" | "R-18G");
+            if is_r18 {
+                tags.push(UnsyncTag { name: name.to_lowercase(), platform: None });
+                continue;
+            }
+
+            let translation = language
+                .and_then(|language| match language {
+                    "romaji" => tag.romaji.as_ref(),
+                    language => tag.translation.get(language),
+                })
+                .filter(|translation| !translation.is_empty() && **translation != name)
+                .cloned();
 
-                let is_r18 = matches!(name.as_str(), "R-18" | "R-18G");
-                if is_r18 {
-                    UnsyncTag { name: name.to_lowercase(), platform: None }
-                } else {
-                    UnsyncTag { name, platform: Some(platform) }
-                }
-            })
-            .collect()
+            tags.push(UnsyncTag { name, platform: Some(platform) });
+            if let Some(translation) = translation
+                && !tags.iter().any(|tag| tag.name == translation)
+            {
+                tags.push(UnsyncTag { name: translation, platform: Some(platform) });
+            }
+        }
+        tags
     }
 }
 
@@ -34,4 +50,10 @@ pub struct PixivTag {
     pub tag: String,
     pub locked: bool,
     pub deletable: bool,
+    /// translations by language, e.g. `en`
+    #[serde(default)]
+    pub translation: HashMap<String, String>,
+    /// reading of the tag in latin letters, used by `--tag-language romaji`
+    #[serde(default)]
+    pub romaji: Option<String>,
 }
@@ -0,0 +1,219 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{info, warn};
+use reqwest::{Response, StatusCode, header};
+use tokio::time::Instant;
+
+/// Pause of all requests while Pixiv asks to slow down with `429 Too Many Requests`,
+/// and with `--adaptive-limit` the spacing of requests adjusted to the recent responses
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    multi: MultiProgress,
+    state: Arc<Mutex<ThrottleState>>,
+}
+
+#[derive(Debug, Default)]
+struct ThrottleState {
+    until: Option<Instant>,
+    events: u32,
+    bar: Option<ProgressBar>,
+    adaptive: Option<AdaptiveRate>,
+}
+
+/// Spacing of requests, doubled on bursts of `429`/`403` and slowly shortened back
+/// to the one of `--limit` while the responses are fine
+#[derive(Debug)]
+struct AdaptiveRate {
+    fastest: Duration,
+    interval: Duration,
+    next: Instant,
+    /// whether each of the recent responses was blocked
+    recent: VecDeque<bool>,
+    successes: u32,
+}
+
+impl AdaptiveRate {
+    const WINDOW: usize = 20;
+    const BURST: usize = 3;
+    const RAMP_UP_AFTER: u32 = 50;
+    const SLOWEST: Duration = Duration::from_secs(30);
+
+    fn new(limit: u32) -> Self {
+        let fastest = Duration::from_secs(60) / limit.max(1);
+        Self {
+            fastest,
+            interval: fastest,
+            next: Instant::now(),
+            recent: VecDeque::with_capacity(Self::WINDOW),
+            successes: 0,
+        }
+    }
+
+    /// Reserve the next slot to send a request at
+    fn reserve(&mut self) -> Instant {
+        let slot = self.next.max(Instant::now());
+        self.next = slot + self.interval;
+        slot
+    }
+
+    fn record(&mut self, status: StatusCode) {
+        let blocked = matches!(
+            status,
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::FORBIDDEN
+        );
+        if self.recent.len() == Self::WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(blocked);
+
+        let bursting = status == StatusCode::TOO_MANY_REQUESTS
+            || self.recent.iter().filter(|blocked| **blocked).count() >= Self::BURST;
+        if bursting {
+            self.recent.clear();
+            self.successes = 0;
+            self.interval = (self.interval * 2).min(Self::SLOWEST);
+            info!(
+                "[throttle] Slowing down to {:.1} requests/min",
+                Self::per_minute(self.interval)
+            );
+            return;
+        }
+
+        if blocked {
+            return;
+        }
+        self.successes += 1;
+        if self.successes >= Self::RAMP_UP_AFTER && self.interval > self.fastest {
+            self.successes = 0;
+            self.interval = self.interval.mul_f64(0.9).max(self.fastest);
+            info!(
+                "[throttle] Speeding up to {:.1} requests/min",
+                Self::per_minute(self.interval)
+            );
+        }
+    }
+
+    fn per_minute(interval: Duration) -> f64 {
+        60.0 / interval.as_secs_f64()
+    }
+}
+
+impl Throttle {
+    /// Used when Pixiv doesn't send `Retry-After`
+    const DEFAULT_PAUSE: Duration = Duration::from_secs(60);
+
+    /// With `adaptive`, requests are spaced to at most `limit` per minute
+    pub fn new(multi: MultiProgress, adaptive: bool, limit: u32) -> Self {
+        let state = ThrottleState {
+            adaptive: adaptive.then(|| AdaptiveRate::new(limit)),
+            ..Default::default()
+        };
+        Self {
+            multi,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Pause if the response is a `429`, for as long as its `Retry-After` asks
+    pub fn check(&self, response: &Response) {
+        if let Some(adaptive) = &mut self.state.lock().unwrap().adaptive {
+            adaptive.record(response.status());
+        }
+
+        if response.status() != StatusCode::TOO_MANY_REQUESTS {
+            return;
+        }
+
+        let delay = response
+            .headers()
+            .get(header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after)
+            .unwrap_or(Self::DEFAULT_PAUSE);
+        self.pause(delay, "rate limited");
+    }
+
+    pub fn pause(&self, delay: Duration, reason: &str) {
+        let until = Instant::now() + delay;
+        let mut state = self.state.lock().unwrap();
+        if state.until.is_some_and(|current| current >= until) {
+            return;
+        }
+
+        state.until = Some(until);
+        state.events += 1;
+        warn!(
+            "[throttle] Requests {reason} by Pixiv, pausing for {}s",
+            delay.as_secs()
+        );
+
+        let message = format!(
+            "{reason} ({} times), paused for {}s",
+            state.events,
+            delay.as_secs()
+        );
+        match &state.bar {
+            Some(bar) => bar.set_message(message),
+            None => {
+                let bar = self.multi.add(
+                    ProgressBar::new_spinner()
+                        .with_style(
+                            ProgressStyle::with_template("{prefix:.bold.dim} {spinner} {msg:.yellow}")
+                                .unwrap(),
+                        )
+                        .with_prefix("[throttle]")
+                        .with_message(message),
+                );
+                bar.enable_steady_tick(Duration::from_millis(200));
+                state.bar = Some(bar);
+            }
+        }
+    }
+
+    /// Wait until the pause is over, if any, and for the next slot of the adaptive rate
+    /// when `paced`, as only the api requests are spaced by it
+    pub async fn wait(&self, paced: bool) {
+        loop {
+            let until = self.state.lock().unwrap().until;
+            match until {
+                Some(until) if until > Instant::now() => tokio::time::sleep_until(until).await,
+                _ => break,
+            }
+        }
+
+        let slot = {
+            let mut state = self.state.lock().unwrap();
+            if state.until.is_some_and(|until| until <= Instant::now()) {
+                state.until = None;
+                if let Some(bar) = state.bar.take() {
+                    bar.finish_and_clear();
+                }
+            }
+            match paced {
+                true => state.adaptive.as_mut().map(AdaptiveRate::reserve),
+                false => None,
+            }
+        };
+
+        if let Some(slot) = slot {
+            tokio::time::sleep_until(slot).await;
+        }
+    }
+}
+
+/// `Retry-After` is either seconds or an http date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    let seconds = (date.timestamp() - Utc::now().timestamp()).max(0);
+    Some(Duration::from_secs(seconds as u64))
+}
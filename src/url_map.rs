@@ -0,0 +1,47 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+
+/// Map of Pixiv urls to the local paths they were archived to, saved as `url_map.json`
+#[derive(Debug, Clone)]
+pub struct UrlMap {
+    path: PathBuf,
+    root: PathBuf,
+    inner: BTreeMap<String, String>,
+}
+
+impl UrlMap {
+    pub fn open(root: &Path) -> Self {
+        let path = root.join("url_map.json");
+        let inner = fs::read(&path)
+            .ok()
+            .and_then(|data| {
+                serde_json::from_slice(&data)
+                    .inspect_err(|e| warn!("[url_map] Ignoring invalid {}: {e}", path.display()))
+                    .ok()
+            })
+            .unwrap_or_default();
+
+        Self {
+            path,
+            root: root.to_path_buf(),
+            inner,
+        }
+    }
+
+    pub fn insert(&mut self, url: String, path: &Path) {
+        let path = path.strip_prefix(&self.root).unwrap_or(path);
+        self.inner.insert(url, path.to_string_lossy().replace('\\', "/"));
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, serde_json::to_vec_pretty(&self.inner)?)?;
+        fs::rename(temp, &self.path)
+    }
+}
@@ -1,24 +1,32 @@
 use std::{
     collections::{HashMap, hash_map::Entry},
     fmt::Debug,
+    path::Path,
+    sync::{Arc, Mutex},
 };
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use plyne::{Input, Output};
 use post_archiver::{
     AuthorId, PlatformId,
     importer::{UnsyncAlias, UnsyncAuthor},
-    manager::PostArchiverManager,
 };
-use post_archiver_utils::{Error, Result};
+use post_archiver_utils::Result;
 use serde::Deserialize;
-use tokio::sync::MutexGuard;
-use tokio::task::JoinSet;
+use serde_json::json;
+use tokio::{sync::OnceCell, task::JoinSet};
 
 use crate::{
+    Manager,
     api::{NullableBody, PixivClient},
     artwork::{PixivArtwork, PixivArtworkId},
+    checkpoint::Checkpoint,
     config::{Config, Progress},
+    failed::FailedWorks,
+    remote::{Remote, copy_file, write_file},
+    file::gzip,
+    series::PixivSeriesId,
+    storage::{Metadata, Storage},
 };
 
 pub type PixivUserId = u64;
@@ -30,8 +38,15 @@ pub struct PixivUserArtworks {
     pub manga: NullableBody<HashMap<String, ()>>,
     pub novels: NullableBody<HashMap<String, ()>>,
     // series are novel and manga of set
-    // pub manga_series: HashMap<String, ()>,
-    // pub novel_series: HashMap<String, ()>,
+    #[serde(default)]
+    pub manga_series: Vec<PixivUserSeries>,
+    #[serde(default)]
+    pub novel_series: Vec<PixivUserSeries>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PixivUserSeries {
+    pub id: String,
 }
 
 impl PixivUserArtworks {
@@ -67,49 +82,168 @@ impl PixivUserArtworks {
 
         artworks
     }
+
+    pub fn newest(&self) -> NewestWorks {
+        let newest = |works: &[&NullableBody<HashMap<String, ()>>]| {
+            works
+                .iter()
+                .filter_map(|works| match works {
+                    NullableBody::Some(works) => Some(works),
+                    NullableBody::None(_) => None,
+                })
+                .flat_map(|works| works.keys())
+                .filter_map(|id| id.parse().ok())
+                .max()
+        };
+        NewestWorks {
+            illust: newest(&[&self.illusts, &self.manga]),
+            novel: newest(&[&self.novels]),
+        }
+    }
+
+    pub fn series(&self) -> Vec<PixivSeriesId> {
+        self.manga_series
+            .iter()
+            .filter_map(|series| series.id.parse().ok())
+            .map(PixivSeriesId::Illust)
+            .chain(
+                self.novel_series
+                    .iter()
+                    .filter_map(|series| series.id.parse().ok())
+                    .map(PixivSeriesId::Novel),
+            )
+            .collect()
+    }
+}
+
+/// Newest illust and novel of a user, the ids of both kinds don't compare with each other
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NewestWorks {
+    pub illust: Option<u64>,
+    pub novel: Option<u64>,
+}
+
+/// Newest works of the users listed by earlier runs, kept by the storage
+/// (the `pixiv_user_sync` table of PostArchiver)
+#[derive(Debug, Clone, Default)]
+pub struct UserSync(Arc<HashMap<PixivUserId, NewestWorks>>);
+
+impl UserSync {
+    /// The last sync, unless the run goes through all works anyway
+    pub async fn open(manager: &Manager, config: &Config) -> Option<Self> {
+        // --update and --overwrite refresh the archived works too
+        if config.full_sync || config.update || config.overwrite {
+            return None;
+        }
+        manager
+            .lock()
+            .await
+            .user_sync()
+            .map(|users| Self(Arc::new(users)))
+            .inspect_err(|e| warn!("[user] Failed to load the last sync of users: {e}"))
+            .ok()
+    }
+
+    /// Whether the user had the same newest works on the last run
+    pub fn is_unchanged(&self, user: PixivUserId, newest: NewestWorks) -> bool {
+        self.0.get(&user) == Some(&newest)
+    }
+}
+
+/// Users listed completely by this run with the urls of their works, the sync of each one is
+/// only saved once all of them are archived, so an interrupted or failed run lists them again
+#[derive(Debug, Clone, Default)]
+pub struct ResolvedUsers(Arc<Mutex<Vec<(PixivUserId, NewestWorks, Vec<String>)>>>);
+
+impl ResolvedUsers {
+    pub fn add(&self, user: PixivUserId, newest: NewestWorks, works: Vec<String>) {
+        self.0.lock().unwrap().push((user, newest, works));
+    }
+
+    /// Save the sync of the users without works still pending or failed, called after the archive
+    pub fn save(&self, storage: &dyn Storage, checkpoint: &Checkpoint, failed: &FailedWorks) {
+        let resolved = std::mem::take(&mut *self.0.lock().unwrap());
+        for (user, newest, works) in resolved {
+            if works
+                .iter()
+                .any(|url| checkpoint.contains(url) || failed.contains(url))
+            {
+                debug!("[user] Works of {user} are left to archive, listing them again next run");
+                continue;
+            }
+            if let Err(e) = storage.save_user_sync(user, newest) {
+                warn!("[user] Failed to save the last sync of {user}: {e}");
+            }
+        }
+    }
 }
 
 pub async fn reslove_users(
     mut users_pipeline: Output<PixivUserId>,
     artworks_pipeline: Input<PixivArtworkId>,
+    series_pipeline: Input<PixivSeriesId>,
+    manager: &Manager,
     config: &Config,
     client: &PixivClient,
 ) {
     let mut join_set = JoinSet::new();
-    let pb = Progress::new(config.multi.clone(), "user");
+    let pb = Progress::new(config, "user");
+
+    let sync = UserSync::open(manager, config).await;
 
     debug!("[user] Waiting for user to resolve");
     while let Some(user) = users_pipeline.recv().await {
+        if config.exclude_users.contains(&user) {
+            info!("[user] Skipping excluded user {user}");
+            continue;
+        }
+
         let pb = pb.clone();
         pb.inc_length(1);
 
         let client = client.clone();
         let tx = artworks_pipeline.clone();
+        let series_tx = series_pipeline.clone();
+        let max_per_user = config.max_per_user;
+        let sync = sync.clone();
+        let resolved = config.resolved_users.clone();
         join_set.spawn(async move {
-            reslove_user(tx, client, user).await;
+            let listed = reslove_user(tx, series_tx, client, user, max_per_user, sync).await;
+            if let Some((newest, works)) = listed {
+                resolved.add(user, newest, works);
+            }
             info!("[user] Resolved {user}");
             pb.inc(1);
         });
     }
 
-    if join_set.is_empty() {
-        return;
-    }
     join_set.join_all().await;
-
     info!("[user] Resolve finished");
 }
 
-async fn reslove_user(tx: Input<PixivArtworkId>, client: PixivClient, id: PixivUserId) {
+async fn reslove_user(
+    tx: Input<PixivArtworkId>,
+    series_tx: Input<PixivSeriesId>,
+    client: PixivClient,
+    id: PixivUserId,
+    max_per_user: Option<usize>,
+    sync: Option<UserSync>,
+) -> Option<(NewestWorks, Vec<String>)> {
     let url = format!("https://www.pixiv.net/ajax/user/{id}/profile/all?lang=ja");
     let user_artworks = match client.fetch::<PixivUserArtworks>(&url).await {
         Ok(artworks) => artworks,
         Err(e) => {
             error!("[user] Failed to fetch {id}: {e:?}");
-            return;
+            return None;
         }
     };
 
+    let newest = user_artworks.newest();
+    if sync.is_some_and(|sync| sync.is_unchanged(id, newest)) {
+        info!("[user] No new works of {id} since the last run, skipping");
+        return None;
+    }
+
     info!("[user] Resloved user {id}");
     if let NullableBody::Some(illusts) = &user_artworks.illusts {
         info!("  + {} illusts", illusts.len());
@@ -120,16 +254,182 @@ async fn reslove_user(tx: Input<PixivArtworkId>, client: PixivClient, id: PixivU
     if let NullableBody::Some(novels) = &user_artworks.novels {
         info!("  + {} novels", novels.len());
     }
+    if !user_artworks.manga_series.is_empty() {
+        info!("  + {} manga series", user_artworks.manga_series.len());
+    }
+    if !user_artworks.novel_series.is_empty() {
+        info!("  + {} novel series", user_artworks.novel_series.len());
+    }
+
+    let Some(max) = max_per_user else {
+        // works of a series are also listed above, the artworks pipeline skips the duplicates
+        for series in user_artworks.series() {
+            series_tx.send(series).ok();
+        }
+        let artworks = user_artworks.list();
+        let works = artworks.iter().map(|artwork| artwork.url()).collect();
+        for artwork in artworks {
+            tx.send(artwork).ok();
+        }
+        return Some((newest, works));
+    };
 
-    for artwork in user_artworks.list() {
+    // ids grow with time, so the highest are the most recent
+    let mut artworks = user_artworks.list();
+    artworks.sort_unstable_by_key(|artwork| std::cmp::Reverse(artwork.id()));
+    // the older works are left out, so the user is not up to date after this run
+    let truncated = artworks.len() > max;
+    if truncated {
+        info!("[user] Archiving the {max} most recent of {} works of {id}", artworks.len());
+        artworks.truncate(max);
+    }
+    let works = artworks.iter().map(|artwork| artwork.url()).collect();
+    for artwork in artworks {
         tx.send(artwork).ok();
     }
+    (!truncated).then_some((newest, works))
+}
+
+/// Id of the user a `pixiv.me/{name}` vanity url redirects to
+pub async fn resolve_vanity(client: &PixivClient, name: &str) -> Option<PixivUserId> {
+    let url = match client.resolve_redirect(&format!("https://pixiv.me/{name}")).await {
+        Ok(url) => url,
+        Err(e) => {
+            error!("[user] Failed to resolve pixiv.me/{name}: {e:?}");
+            return None;
+        }
+    };
+
+    let id = url
+        .path_segments()
+        .and_then(|mut segments| segments.find(|s| *s == "users").and(segments.next()))
+        .and_then(|id| id.parse().ok());
+    if id.is_none() {
+        error!("[user] pixiv.me/{name} redirects to {url}, which is not a user");
+    }
+    id
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PixivUserProfile {
+    pub user_id: String,
+    pub name: String,
+    pub image_big: String,
+    #[serde(default)]
+    pub comment: String,
+    pub background: Option<PixivUserBackground>,
+    pub region: Option<PixivUserRegion>,
+    pub social: NullableBody<HashMap<String, PixivUserSocial>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PixivUserBackground {
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PixivUserRegion {
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PixivUserSocial {
+    pub url: String,
+}
+
+impl PixivUserProfile {
+    pub fn socials(&self) -> Vec<(&str, &str)> {
+        match &self.social {
+            NullableBody::Some(social) => social
+                .iter()
+                .map(|(platform, link)| (platform.as_str(), link.url.as_str()))
+                .collect(),
+            NullableBody::None(_) => vec![],
+        }
+    }
+}
+
+/// The profile with the untouched json of the response
+pub async fn fetch_profile(client: &PixivClient, id: &str) -> Option<(PixivUserProfile, Vec<u8>)> {
+    let url = format!("https://www.pixiv.net/ajax/user/{id}?full=1&lang=ja");
+    client
+        .fetch_with_raw(&url)
+        .await
+        .inspect_err(|e| error!("[user] Failed to fetch profile of {id}: {e:?}"))
+        .ok()
+}
+
+/// Save avatar and banner under `authors/{id}`, with the untouched response as
+/// `profile.raw.json.gz` if given. The bio, region and socials go to the storage,
+/// or to `profile.json` next to the avatar without a database.
+pub async fn save_profile(
+    client: &PixivClient,
+    profile: &PixivUserProfile,
+    raw: Option<&[u8]>,
+    root: &Path,
+    remote: Option<&Remote>,
+    manager: &Manager,
+) {
+    let dir = root.join("authors").join(&profile.user_id);
+
+    let images = [("avatar", Some(&profile.image_big))]
+        .into_iter()
+        .chain([("banner", profile.background.as_ref().map(|b| &b.url))]);
+    for (name, url) in images {
+        let Some(url) = url else { continue };
+        let ext = url.rsplit_once('.').map(|(_, ext)| ext).unwrap_or("jpg");
+        let dst = dir.join(format!("{name}.{ext}"));
+        match client.download(url).await {
+            Ok(temp) => {
+                if let Err(e) = copy_file(remote, &temp, &dst).await {
+                    error!("[user] Failed to save {}: {e}", dst.display());
+                }
+            }
+            Err(e) => error!("[user] Failed to download {name} of {}: {e}", profile.user_id),
+        }
+    }
+
+    let metadata = json!({
+        "id": profile.user_id,
+        "name": profile.name,
+        "bio": profile.comment,
+        "region": profile.region.as_ref().and_then(|region| region.name.clone()),
+        "social": profile.socials().into_iter().collect::<HashMap<_, _>>(),
+    });
+    let kept = manager
+        .lock()
+        .await
+        .set_metadata(&Metadata::Profile(profile.user_id.clone()), &metadata);
+    match kept {
+        Ok(true) => {}
+        Ok(false) => {
+            let path = dir.join("profile.json");
+            let content = serde_json::to_vec_pretty(&metadata).unwrap();
+            if let Err(e) = write_file(remote, &path, &content).await {
+                error!("[user] Failed to save {}: {e}", path.display());
+            }
+        }
+        Err(e) => error!("[user] Failed to save the profile of {}: {e}", profile.user_id),
+    }
+
+    if let Some(raw) = raw {
+        let path = dir.join("profile.raw.json.gz");
+        let result = match gzip(raw) {
+            Ok(content) => write_file(remote, &path, &content).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("[user] Failed to save {}: {e}", path.display());
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct UserManager {
     pub platform: PlatformId,
     inner: HashMap<String, AuthorId>,
+    profiles: HashMap<String, Arc<OnceCell<Option<PixivUserProfile>>>>,
 }
 
 impl UserManager {
@@ -137,25 +437,41 @@ impl UserManager {
         Self {
             platform,
             inner: Default::default(),
+            profiles: Default::default(),
         }
     }
+
+    /// Reserve the profile of the user, so it is fetched once by the first to initialize it
+    pub fn profile(&mut self, user_id: &str) -> Arc<OnceCell<Option<PixivUserProfile>>> {
+        self.profiles.entry(user_id.to_string()).or_default().clone()
+    }
+
     pub fn import(
         &mut self,
-        manager: &MutexGuard<'_, PostArchiverManager>,
+        storage: &dyn Storage,
         artwork: &PixivArtwork,
+        profile: Option<&PixivUserProfile>,
     ) -> Result<AuthorId> {
         match self.inner.entry(artwork.user_id.clone()) {
             Entry::Occupied(occupied_entry) => Ok(*occupied_entry.get()),
-            Entry::Vacant(vacant_entry) => UnsyncAuthor::new(artwork.user_name.clone())
-                .aliases(vec![
+            Entry::Vacant(vacant_entry) => {
+                let mut aliases = vec![
                     UnsyncAlias::new(self.platform, artwork.user_id.clone())
                         .link(format!("https://www.pixiv.net/users/{}", artwork.user_id)),
-                ])
-                .sync(manager)
-                .inspect(|id| {
+                ];
+
+                // social links become aliases on their own platforms
+                for (platform, link) in profile.map(|p| p.socials()).unwrap_or_default() {
+                    let platform = storage.import_platform(platform)?;
+                    let name = link.trim_end_matches('/').rsplit('/').next().unwrap_or(link);
+                    aliases.push(UnsyncAlias::new(platform, name.to_string()).link(link.to_string()));
+                }
+
+                let author = UnsyncAuthor::new(artwork.user_name.clone()).aliases(aliases);
+                storage.import_author(author).inspect(|id| {
                     vacant_entry.insert(*id);
                 })
-                .map_err(Error::from),
+            }
         }
     }
 }
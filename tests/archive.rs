@@ -0,0 +1,105 @@
+//! Archive works from a mock of pixiv, through the `--origin` of the client
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use image::{DynamicImage, ImageFormat};
+use pixiv_archive::{
+    Archiver,
+    storage::{self, Storage},
+};
+use serde_json::Value;
+use wiremock::{
+    Mock, MockServer, ResponseTemplate,
+    matchers::{method, path, path_regex},
+};
+
+const MANGA: &str = "https://www.pixiv.net/artworks/100";
+const DELETED: &str = "https://www.pixiv.net/artworks/200";
+
+fn fixture(name: &str) -> Vec<u8> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+    std::fs::read(path).unwrap()
+}
+
+/// The 8x8 png served for every page
+fn png() -> Vec<u8> {
+    let mut png = std::io::Cursor::new(vec![]);
+    DynamicImage::new_rgb8(8, 8)
+        .write_to(&mut png, ImageFormat::Png)
+        .unwrap();
+    png.into_inner()
+}
+
+async fn mock_pixiv() -> MockServer {
+    let server = MockServer::start().await;
+    let json = |status: u16, name: &str| {
+        ResponseTemplate::new(status).set_body_raw(fixture(name), "application/json")
+    };
+
+    Mock::given(method("GET"))
+        .and(path("/ajax/illust/100"))
+        .respond_with(json(200, "illust_100.json"))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/ajax/illust/100/pages"))
+        .respond_with(json(200, "illust_100_pages.json"))
+        .mount(&server)
+        .await;
+    // a deleted work is answered once, without retrying
+    Mock::given(method("GET"))
+        .and(path("/ajax/illust/200"))
+        .respond_with(json(400, "illust_200_deleted.json"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/img-original/img/.+/100_p[01]\.png$"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(png(), "image/png"))
+        .expect(2)
+        .mount(&server)
+        .await;
+    server
+}
+
+#[tokio::test]
+async fn archive_manga_and_deleted_work() {
+    let server = mock_pixiv().await;
+    let output = tempfile::tempdir().unwrap();
+
+    let archived = Arc::new(Mutex::new(vec![]));
+    let hook = archived.clone();
+    let archiver = Archiver::builder("session", output.path())
+        .target(MANGA)
+        .unwrap()
+        .target(DELETED)
+        .unwrap()
+        .configure(|config| {
+            config.origin = Some(server.uri().parse().unwrap());
+            config.limit = 600;
+        })
+        .on_archived(move |source, _, _| hook.lock().unwrap().push(source.to_string()))
+        .build()
+        .unwrap();
+    archiver.run().await.unwrap();
+
+    assert_eq!(*archived.lock().unwrap(), [MANGA]);
+
+    let storage = storage::open(archiver.config()).unwrap();
+    let post = storage.find_post(MANGA).unwrap().expect("the manga is archived");
+    let dir = storage.post_path(output.path(), post);
+    for page in ["100_p0.png", "100_p1.png"] {
+        assert_eq!(std::fs::read(dir.join(page)).unwrap(), png(), "{page}");
+    }
+    assert!(storage.find_post(DELETED).unwrap().is_none());
+
+    let deleted = std::fs::read(output.path().join("deleted.json")).unwrap();
+    let deleted: Value = serde_json::from_slice(&deleted).unwrap();
+    let tombstone = &deleted[DELETED];
+    assert_eq!(tombstone["reason"], "該当作品は削除されたか、存在しない作品IDです。");
+    assert_eq!(tombstone["archived"], false);
+    assert!(deleted.get(MANGA).is_none());
+}